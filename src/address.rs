@@ -0,0 +1,484 @@
+// see https://dbus.freedesktop.org/doc/dbus-specification.html#addresses
+
+use std::ffi::OsStr;
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::path::PathBuf;
+
+use crate::utils::{random_hex, DbusError, Result};
+
+/// Errors that can occur while parsing a dbus address string
+#[derive(Debug, PartialEq, Eq)]
+pub enum AddressError {
+    /// The address string did not contain any address
+    Empty,
+    /// An address did not have the `transport:` prefix
+    MissingTransport(String),
+    /// A key-value pair was not of the form `key=value`
+    MalformedPair(String),
+    /// A `%` escape was not followed by two hex digits
+    InvalidEscape(String),
+    /// A value contained a character which must have been escaped
+    InvalidCharacter(char),
+    /// Same key was specified more than once in an address
+    DuplicateKey(String),
+    /// A key required by the transport was not given
+    MissingKey {
+        transport: String,
+        key: &'static str,
+    },
+    /// Keys which cannot be used together were given in same address
+    ConflictingKeys {
+        transport: String,
+        keys: Vec<String>,
+    },
+    /// A key had a value which is not valid for it
+    InvalidValue { key: String, value: String },
+    /// The transport is not one we know how to connect to
+    UnsupportedTransport(String),
+}
+
+impl From<AddressError> for DbusError {
+    fn from(err: AddressError) -> DbusError {
+        DbusError::AddressError(err)
+    }
+}
+
+/// Transport over which the dbus connection is to be made
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Transport {
+    /// Unix socket at given filesystem path
+    UnixPath(PathBuf),
+    /// Unix socket in the abstract namespace, with given name (without the leading null byte)
+    UnixAbstract(Vec<u8>),
+    /// Unix socket with a generated name in given directory.
+    /// This can only be listened on, and must be resolved into
+    /// an actual address with `DbusAddress::for_listening` first
+    UnixTmpdir(PathBuf),
+    /// TCP socket at given host and port, optionally restricted to given address family
+    Tcp {
+        host: String,
+        port: u16,
+        family: Option<TcpFamily>,
+    },
+}
+
+/// Address family to use when resolving the host of tcp address
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// A single parsed dbus server address
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DbusAddress {
+    pub transport: Transport,
+    /// guid of the server, if one was given in the address
+    pub guid: Option<String>,
+}
+
+/// characters which can appear in a value without being escaped
+fn is_optionally_escaped(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'/' | b'.' | b'\\' | b'*')
+}
+
+/// Un-escapes the %xx encoded bytes in given address value
+fn unescape_value(value: &str) -> Result<Vec<u8>> {
+    let bytes = value.as_bytes();
+    let mut ret = Vec::with_capacity(bytes.len());
+    let mut ctr = 0;
+
+    while ctr < bytes.len() {
+        let byte = bytes[ctr];
+        if byte == b'%' {
+            // from_str_radix would accept a sign as well, so check the digits ourselves
+            let hex = bytes
+                .get(ctr + 1..ctr + 3)
+                .filter(|h| h.iter().all(u8::is_ascii_hexdigit))
+                .and_then(|h| std::str::from_utf8(h).ok())
+                .and_then(|h| u8::from_str_radix(h, 16).ok());
+            match hex {
+                Some(v) => ret.push(v),
+                None => return Err(AddressError::InvalidEscape(value.to_string()).into()),
+            }
+            ctr += 3;
+        } else if is_optionally_escaped(byte) {
+            ret.push(byte);
+            ctr += 1;
+        } else {
+            // we can unwrap, as the ctr is always at a char boundary here :
+            // all multibyte chars are rejected as soon as their first byte is seen
+            let c = value[ctr..].chars().next().unwrap();
+            return Err(AddressError::InvalidCharacter(c).into());
+        }
+    }
+    Ok(ret)
+}
+
+/// Escapes given bytes so they can be used as a value in dbus address
+pub fn escape_value(value: &[u8]) -> String {
+    value
+        .iter()
+        .map(|b| {
+            if is_optionally_escaped(*b) {
+                (*b as char).to_string()
+            } else {
+                format!("%{:02x}", b)
+            }
+        })
+        .collect()
+}
+
+/// key and un-escaped value pairs of an address
+type AddressPairs<'a> = Vec<(&'a str, Vec<u8>)>;
+
+/// Splits a single address into its transport and key-value pairs, un-escaping the values
+fn split_address(address: &str) -> Result<(&str, AddressPairs<'_>)> {
+    let (transport, rest) = match address.split_once(':') {
+        Some((t, r)) if !t.is_empty() => (t, r),
+        _ => return Err(AddressError::MissingTransport(address.to_string()).into()),
+    };
+
+    let mut pairs: AddressPairs = Vec::new();
+
+    for pair in rest.split(',').filter(|p| !p.is_empty()) {
+        let (key, value) = match pair.split_once('=') {
+            Some((k, v)) if !k.is_empty() && !v.is_empty() => (k, v),
+            _ => return Err(AddressError::MalformedPair(pair.to_string()).into()),
+        };
+        if pairs.iter().any(|(k, _)| *k == key) {
+            return Err(AddressError::DuplicateKey(key.to_string()).into());
+        }
+        pairs.push((key, unescape_value(value)?));
+    }
+
+    Ok((transport, pairs))
+}
+
+fn get_key<'a>(pairs: &'a [(&str, Vec<u8>)], key: &str) -> Option<&'a [u8]> {
+    pairs
+        .iter()
+        .find(|(k, _)| *k == key)
+        .map(|(_, v)| v.as_slice())
+}
+
+fn bytes_to_string(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec())
+        .map_err(|_| AddressError::InvalidCharacter(char::REPLACEMENT_CHARACTER).into())
+}
+
+/// Gets the unix transport from given address keys, exactly one of
+/// path, abstract and tmpdir must be given
+fn parse_unix(pairs: &[(&str, Vec<u8>)]) -> Result<Transport> {
+    let keys: Vec<_> = pairs
+        .iter()
+        .filter(|(k, _)| matches!(*k, "path" | "abstract" | "tmpdir"))
+        .collect();
+
+    match keys.as_slice() {
+        [] => Err(AddressError::MissingKey {
+            transport: "unix".to_string(),
+            key: "path, abstract or tmpdir",
+        }
+        .into()),
+        [(key, value)] => Ok(match *key {
+            "path" => Transport::UnixPath(OsStr::from_bytes(value).into()),
+            "abstract" => Transport::UnixAbstract(value.clone()),
+            _ => Transport::UnixTmpdir(OsStr::from_bytes(value).into()),
+        }),
+        _ => Err(AddressError::ConflictingKeys {
+            transport: "unix".to_string(),
+            keys: keys.iter().map(|(k, _)| k.to_string()).collect(),
+        }
+        .into()),
+    }
+}
+
+/// Gets the tcp transport from given address keys, port is required,
+/// and host defaults to localhost as in the reference implementation
+fn parse_tcp(pairs: &[(&str, Vec<u8>)]) -> Result<Transport> {
+    let host = match get_key(pairs, "host") {
+        Some(h) => bytes_to_string(h)?,
+        None => "localhost".to_string(),
+    };
+
+    let port = match get_key(pairs, "port") {
+        Some(p) => {
+            let p = bytes_to_string(p)?;
+            p.parse().map_err(|_| AddressError::InvalidValue {
+                key: "port".to_string(),
+                value: p,
+            })?
+        }
+        None => {
+            return Err(AddressError::MissingKey {
+                transport: "tcp".to_string(),
+                key: "port",
+            }
+            .into())
+        }
+    };
+
+    let family = match get_key(pairs, "family") {
+        None => None,
+        Some(b"ipv4") => Some(TcpFamily::Ipv4),
+        Some(b"ipv6") => Some(TcpFamily::Ipv6),
+        Some(f) => {
+            return Err(AddressError::InvalidValue {
+                key: "family".to_string(),
+                value: String::from_utf8_lossy(f).into(),
+            }
+            .into())
+        }
+    };
+
+    Ok(Transport::Tcp { host, port, family })
+}
+
+impl DbusAddress {
+    /// Parse a single address of form `transport:key=value,key=value`
+    pub fn parse(address: &str) -> Result<Self> {
+        let (transport, pairs) = split_address(address)?;
+
+        let guid = get_key(&pairs, "guid").map(bytes_to_string).transpose()?;
+
+        let transport = match transport {
+            "unix" => parse_unix(&pairs)?,
+            "tcp" => parse_tcp(&pairs)?,
+            _ => return Err(AddressError::UnsupportedTransport(transport.to_string()).into()),
+        };
+
+        Ok(Self { transport, guid })
+    }
+
+    /// Parse a `;` separated list of addresses, such as the value of
+    /// `DBUS_SESSION_BUS_ADDRESS`. The addresses are returned in the order given,
+    /// which is the order in which they should be tried
+    pub fn parse_list(addresses: &str) -> Result<Vec<Self>> {
+        let ret = addresses
+            .split(';')
+            .filter(|a| !a.is_empty())
+            .map(Self::parse)
+            .collect::<Result<Vec<_>>>()?;
+
+        if ret.is_empty() {
+            return Err(AddressError::Empty.into());
+        }
+        Ok(ret)
+    }
+
+    /// Get the concrete address to listen on for this address.
+    /// For `unix:tmpdir=` this generates a unique socket name under the directory,
+    /// which is placed in the abstract namespace, same as the reference implementation
+    /// does on linux. All other addresses are returned as they are.
+    pub fn for_listening(&self) -> Result<Self> {
+        let transport = match &self.transport {
+            Transport::UnixTmpdir(dir) => {
+                let path = dir.join(format!("dbus-{}", random_hex(8)?));
+                Transport::UnixAbstract(path.into_os_string().into_vec())
+            }
+            t => t.clone(),
+        };
+        Ok(Self {
+            transport,
+            guid: self.guid.clone(),
+        })
+    }
+}
+
+impl std::fmt::Display for DbusAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.transport {
+            Transport::UnixPath(path) => {
+                write!(f, "unix:path={}", escape_value(path.as_os_str().as_bytes()))?;
+            }
+            Transport::UnixAbstract(name) => {
+                write!(f, "unix:abstract={}", escape_value(name))?;
+            }
+            Transport::UnixTmpdir(dir) => {
+                write!(
+                    f,
+                    "unix:tmpdir={}",
+                    escape_value(dir.as_os_str().as_bytes())
+                )?;
+            }
+            Transport::Tcp { host, port, family } => {
+                write!(
+                    f,
+                    "tcp:host={},port={}",
+                    escape_value(host.as_bytes()),
+                    port
+                )?;
+                match family {
+                    Some(TcpFamily::Ipv4) => write!(f, ",family=ipv4")?,
+                    Some(TcpFamily::Ipv6) => write!(f, ",family=ipv6")?,
+                    None => {}
+                }
+            }
+        }
+        if let Some(guid) = &self.guid {
+            write!(f, ",guid={}", escape_value(guid.as_bytes()))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn address_error(addr: &str) -> AddressError {
+        match DbusAddress::parse_list(addr) {
+            Err(DbusError::AddressError(e)) => e,
+            v => panic!("expected address error for {}, got {:?}", addr, v),
+        }
+    }
+
+    #[test]
+    fn test_parse_unix_path() {
+        let addr = DbusAddress::parse("unix:path=/run/user/1000/bus").unwrap();
+        assert_eq!(
+            addr.transport,
+            Transport::UnixPath("/run/user/1000/bus".into())
+        );
+        assert_eq!(addr.guid, None);
+
+        let addr =
+            DbusAddress::parse("unix:path=/tmp/dbus%20test,guid=1d8a1b23e6e8c5dbb0b1b0d565a1f3f2")
+                .unwrap();
+        assert_eq!(addr.transport, Transport::UnixPath("/tmp/dbus test".into()));
+        assert_eq!(
+            addr.guid.as_deref(),
+            Some("1d8a1b23e6e8c5dbb0b1b0d565a1f3f2")
+        );
+
+        // display must give back an equivalent address
+        assert_eq!(DbusAddress::parse(&addr.to_string()).unwrap(), addr);
+    }
+
+    #[test]
+    fn test_parse_unix_abstract_and_tmpdir() {
+        let addr = DbusAddress::parse("unix:abstract=/tmp/dbus-test%00x").unwrap();
+        assert_eq!(
+            addr.transport,
+            Transport::UnixAbstract(b"/tmp/dbus-test\0x".to_vec())
+        );
+        assert_eq!(DbusAddress::parse(&addr.to_string()).unwrap(), addr);
+        // non-tmpdir addresses are listened on as they are
+        assert_eq!(addr.for_listening().unwrap(), addr);
+
+        let addr = DbusAddress::parse("unix:tmpdir=/tmp").unwrap();
+        assert_eq!(addr.transport, Transport::UnixTmpdir("/tmp".into()));
+        let listen = addr.for_listening().unwrap();
+        match listen.transport {
+            Transport::UnixAbstract(name) => assert!(name.starts_with(b"/tmp/dbus-")),
+            t => panic!("expected abstract address, got {:?}", t),
+        }
+    }
+
+    #[test]
+    fn test_parse_tcp() {
+        let addr = DbusAddress::parse("tcp:host=%3a%3a1,port=4000,family=ipv6").unwrap();
+        assert_eq!(
+            addr.transport,
+            Transport::Tcp {
+                host: "::1".into(),
+                port: 4000,
+                family: Some(TcpFamily::Ipv6)
+            }
+        );
+        assert_eq!(DbusAddress::parse(&addr.to_string()).unwrap(), addr);
+
+        let addr = DbusAddress::parse("tcp:port=4000").unwrap();
+        assert_eq!(
+            addr.transport,
+            Transport::Tcp {
+                host: "localhost".into(),
+                port: 4000,
+                family: None
+            }
+        );
+
+        assert_eq!(
+            address_error("tcp:host=localhost"),
+            AddressError::MissingKey {
+                transport: "tcp".into(),
+                key: "port"
+            }
+        );
+        assert_eq!(
+            address_error("tcp:port=70000"),
+            AddressError::InvalidValue {
+                key: "port".into(),
+                value: "70000".into()
+            }
+        );
+        assert_eq!(
+            address_error("tcp:port=1,family=ipx"),
+            AddressError::InvalidValue {
+                key: "family".into(),
+                value: "ipx".into()
+            }
+        );
+    }
+
+    #[test]
+    fn test_parse_list() {
+        let addrs = DbusAddress::parse_list("unix:path=/first;;unix:path=/second;").unwrap();
+        assert_eq!(addrs.len(), 2);
+        assert_eq!(addrs[0].transport, Transport::UnixPath("/first".into()));
+        assert_eq!(addrs[1].transport, Transport::UnixPath("/second".into()));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(address_error(""), AddressError::Empty);
+        assert_eq!(address_error(";"), AddressError::Empty);
+        assert!(matches!(
+            address_error("/run/user/1000/bus"),
+            AddressError::MissingTransport(_)
+        ));
+        assert!(matches!(
+            address_error("unix:path"),
+            AddressError::MalformedPair(_)
+        ));
+        assert!(matches!(
+            address_error("unix:path=/a%2"),
+            AddressError::InvalidEscape(_)
+        ));
+        assert!(matches!(
+            address_error("unix:path=/a%zz"),
+            AddressError::InvalidEscape(_)
+        ));
+        assert!(matches!(
+            address_error("unix:path=/a%+f"),
+            AddressError::InvalidEscape(_)
+        ));
+        assert_eq!(
+            address_error("unix:path=/a b"),
+            AddressError::InvalidCharacter(' ')
+        );
+        assert_eq!(
+            address_error("unix:path=/a,path=/b"),
+            AddressError::DuplicateKey("path".into())
+        );
+        assert_eq!(
+            address_error("unix:guid=abcd"),
+            AddressError::MissingKey {
+                transport: "unix".into(),
+                key: "path, abstract or tmpdir"
+            }
+        );
+        assert_eq!(
+            address_error("unix:path=/a,abstract=b"),
+            AddressError::ConflictingKeys {
+                transport: "unix".into(),
+                keys: vec!["path".into(), "abstract".into()]
+            }
+        );
+        assert_eq!(
+            address_error("launchd:env=FOO"),
+            AddressError::UnsupportedTransport("launchd".into())
+        );
+    }
+}
@@ -0,0 +1,683 @@
+use std::collections::{HashMap, VecDeque};
+use std::future::Future;
+use std::os::unix::io::{AsFd, AsRawFd, FromRawFd, OwnedFd};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Waker};
+
+use futures_core::Stream;
+use nix::fcntl::OFlag;
+use nix::poll::{poll, PollFd, PollFlags};
+
+use crate::dbus::DbusConnection;
+use crate::message::*;
+use crate::names::{BusName, InterfaceName, MemberName, ObjectPath};
+use crate::proxy::{build_method_call, parse_method_reply};
+use crate::serialize::DbusSerialize;
+use crate::utils::{lock, DbusError, Result};
+
+/// Tasks waiting for the socket to become ready
+struct Interest {
+    readable: Vec<Waker>,
+    writable: Vec<Waker>,
+    closed: bool,
+}
+
+/// As we cannot depend on the reactor of any particular runtime, this uses a thread
+/// which waits for the socket to become ready, and wakes up the tasks waiting for it.
+/// The thread only waits, all the reading and writing is done by the tasks themselves
+struct Notifier {
+    interest: Mutex<Interest>,
+    /// written to, to wake the thread up when the interest changes
+    wake_write: OwnedFd,
+}
+
+impl Notifier {
+    /// Registers the task to be woken once the socket is readable, or writable
+    fn register(&self, waker: &Waker, writable: bool) {
+        let mut interest = lock(&self.interest);
+        let wakers = if writable {
+            &mut interest.writable
+        } else {
+            &mut interest.readable
+        };
+        // the thread is already waiting for this event if some task is waiting on it
+        let changed = wakers.is_empty();
+        if !wakers.iter().any(|w| w.will_wake(waker)) {
+            wakers.push(waker.clone());
+        }
+        drop(interest);
+        if changed {
+            self.wake_thread();
+        }
+    }
+
+    fn wake_thread(&self) {
+        // if the pipe is full, the thread has a wake up pending anyway
+        let _ = nix::unistd::write(self.wake_write.as_raw_fd(), &[0]);
+    }
+
+    /// Waits for the socket to become ready for what the tasks are waiting, and wakes them up,
+    /// until the connection is dropped
+    fn run(&self, socket: OwnedFd, wake_read: OwnedFd) {
+        loop {
+            let mut events = PollFlags::empty();
+            {
+                let interest = lock(&self.interest);
+                if interest.closed {
+                    return;
+                }
+                if !interest.readable.is_empty() {
+                    events |= PollFlags::POLLIN;
+                }
+                if !interest.writable.is_empty() {
+                    events |= PollFlags::POLLOUT;
+                }
+            }
+
+            // negative fds are ignored, so we do not spin on hangup when nobody is waiting
+            let socket_fd = if events.is_empty() {
+                -1
+            } else {
+                socket.as_raw_fd()
+            };
+            let mut fds = [
+                PollFd::new(socket_fd, events),
+                PollFd::new(wake_read.as_raw_fd(), PollFlags::POLLIN),
+            ];
+            match poll(&mut fds, -1) {
+                Ok(_) => {}
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(_) => {
+                    // we cannot wait anymore, so let the tasks find out the error themselves
+                    let mut interest = lock(&self.interest);
+                    let mut wakers = std::mem::take(&mut interest.readable);
+                    wakers.append(&mut interest.writable);
+                    drop(interest);
+                    wakers.into_iter().for_each(Waker::wake);
+                    return;
+                }
+            }
+
+            if fds[1].revents().is_some_and(|r| !r.is_empty()) {
+                let mut buf = [0; 64];
+                while let Ok(n) = nix::unistd::read(wake_read.as_raw_fd(), &mut buf) {
+                    if n == 0 {
+                        break;
+                    }
+                }
+            }
+
+            let revents = fds[0].revents().unwrap_or(PollFlags::empty());
+            let failed = revents.intersects(PollFlags::POLLHUP | PollFlags::POLLERR);
+            let mut wakers = vec![];
+            let mut interest = lock(&self.interest);
+            if failed || revents.contains(PollFlags::POLLIN) {
+                wakers.append(&mut interest.readable);
+            }
+            if failed || revents.contains(PollFlags::POLLOUT) {
+                wakers.append(&mut interest.writable);
+            }
+            drop(interest);
+            wakers.into_iter().for_each(Waker::wake);
+        }
+    }
+}
+
+/// Messages which are not replies, waiting to be taken by a signal stream
+struct Subscriber {
+    queue: VecDeque<Message>,
+    waker: Option<Waker>,
+}
+
+struct State {
+    conn: DbusConnection,
+    /// calls whose reply is awaited, along with the task to wake when it arrives
+    waiting: HashMap<u32, Option<Waker>>,
+    /// replies which have arrived, but have not been taken by their task yet
+    replies: HashMap<u32, Message>,
+    subscribers: HashMap<usize, Subscriber>,
+    next_subscriber: usize,
+    connected: bool,
+}
+
+impl State {
+    /// Reads all the messages available without blocking, and routes them to the tasks which
+    /// need them, collecting the wakers of those tasks. Tasks must be woken after the state
+    /// is unlocked, as they might be polled right away
+    fn read_available(&mut self, wake: &mut Vec<Waker>) -> Result<()> {
+        loop {
+            match self.conn.try_read_message() {
+                Ok(Some(msg)) => self.route(msg, wake),
+                Ok(None) => return Ok(()),
+                // a single malformed message can be skipped, as it has already been taken
+                // out of the buffer
+                Err(DbusError::IncorrectMessage(_) | DbusError::ValidationError(_)) => {}
+                Err(e) => {
+                    if self.connected {
+                        self.connected = false;
+                        // this gives the subscribers the Disconnected signal
+                        for msg in self.conn.drain_incoming() {
+                            self.route(msg, wake);
+                        }
+                        wake.extend(self.waiting.values_mut().filter_map(Option::take));
+                        wake.extend(self.subscribers.values_mut().filter_map(|s| s.waker.take()));
+                    }
+                    return Err(e);
+                }
+            }
+        }
+    }
+
+    fn route(&mut self, msg: Message, wake: &mut Vec<Waker>) {
+        match msg.reply_serial() {
+            Some(serial) => {
+                // if no one is waiting, the future was dropped and the reply is discarded
+                if let Some(waker) = self.waiting.get_mut(&serial) {
+                    wake.extend(waker.take());
+                    self.replies.insert(serial, msg);
+                }
+            }
+            None => {
+                // as in SharedConnection, messages are dropped if there are no subscribers
+                let mut msg = Some(msg);
+                let count = self.subscribers.len();
+                for (i, sub) in self.subscribers.values_mut().enumerate() {
+                    // last one gets the original, so we do not copy it needlessly
+                    let m = if i + 1 == count {
+                        msg.take().unwrap()
+                    } else {
+                        match msg.as_ref().unwrap().try_clone() {
+                            Ok(m) => m,
+                            // skipped for this stream, rather than given without its fds
+                            Err(_) => continue,
+                        }
+                    };
+                    sub.queue.push_back(m);
+                    wake.extend(sub.waker.take());
+                }
+            }
+        }
+    }
+}
+
+struct Inner {
+    state: Mutex<State>,
+    notifier: Arc<Notifier>,
+}
+
+impl Drop for Inner {
+    fn drop(&mut self) {
+        lock(&self.notifier.interest).closed = true;
+        self.notifier.wake_thread();
+    }
+}
+
+/// An async dbus connection, which can be used with any executor. The socket is
+/// non-blocking, and the tasks are woken by a helper thread when it becomes ready.
+/// This can be cloned to use the connection from multiple tasks.
+/// There are no timeouts here, instead use the timeout of the runtime, as dropping
+/// the future of a method call safely discards its reply.
+/// Reconnecting is not supported, as the helper thread waits on its own duplicate of
+/// the socket : any reconnect policy set on the given connection is cleared
+#[derive(Clone)]
+pub struct AsyncConnection {
+    inner: Arc<Inner>,
+    unique_name: Option<String>,
+    unix_fd: bool,
+    endian: Endian,
+}
+
+impl AsyncConnection {
+    /// Create an async connection out of given authenticated connection
+    pub fn new(mut conn: DbusConnection) -> Result<Self> {
+        // reconnecting would replace the socket the helper thread waits on
+        conn.set_reconnect_policy(None);
+        conn.set_nonblocking(true)?;
+        // the notifier thread gets its own duplicate, so it can never poll a closed fd
+        let socket = conn.as_fd().try_clone_to_owned()?;
+        let (wake_read, wake_write) = nix::unistd::pipe2(OFlag::O_CLOEXEC | OFlag::O_NONBLOCK)?;
+        // this is safe as we have just created the pipe
+        let (wake_read, wake_write) = unsafe {
+            (
+                OwnedFd::from_raw_fd(wake_read),
+                OwnedFd::from_raw_fd(wake_write),
+            )
+        };
+
+        let notifier = Arc::new(Notifier {
+            interest: Mutex::new(Interest {
+                readable: vec![],
+                writable: vec![],
+                closed: false,
+            }),
+            wake_write,
+        });
+        let thread_notifier = notifier.clone();
+        std::thread::Builder::new()
+            .name("dbus-notifier".into())
+            .spawn(move || thread_notifier.run(socket, wake_read))?;
+
+        let unique_name = conn.unique_name().map(|n| n.to_string());
+        let unix_fd = conn.unix_fd_enabled();
+        let endian = conn.endian();
+        let connected = conn.is_connected();
+        Ok(Self {
+            inner: Arc::new(Inner {
+                state: Mutex::new(State {
+                    conn,
+                    waiting: HashMap::new(),
+                    replies: HashMap::new(),
+                    subscribers: HashMap::new(),
+                    next_subscriber: 0,
+                    connected,
+                }),
+                notifier,
+            }),
+            unique_name,
+            unix_fd,
+            endian,
+        })
+    }
+
+    /// Get the unique name assigned to the connection by the bus
+    pub fn unique_name(&self) -> Option<&str> {
+        self.unique_name.as_deref()
+    }
+
+    /// Check if fds can be passed over this connection
+    pub fn unix_fd_enabled(&self) -> bool {
+        self.unix_fd
+    }
+
+    /// Check if the connection is still open
+    pub fn is_connected(&self) -> bool {
+        lock(&self.inner.state).connected
+    }
+
+    /// Send the message without waiting for any reply, see `DbusConnection::send`.
+    /// Returns the serial of the sent message once it has been completely written
+    pub async fn send(
+        &self,
+        mtype: MessageType,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+    ) -> Result<u32> {
+        self.send_with_flags(mtype, MessageFlags::empty(), headers, body, fds)
+            .await
+    }
+
+    /// Send the message with given flags, see `DbusConnection::send_with_flags`
+    pub async fn send_with_flags(
+        &self,
+        mtype: MessageType,
+        flags: MessageFlags,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+    ) -> Result<u32> {
+        let serial = lock(&self.inner.state)
+            .conn
+            .send_with_flags(mtype, flags, headers, body, fds)?;
+        Flush { conn: self }.await?;
+        Ok(serial)
+    }
+
+    /// Send the message and wait for its reply, see `DbusConnection::send_message`
+    pub async fn send_message(
+        &self,
+        mtype: MessageType,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+    ) -> Result<Message> {
+        let mut message = Message::new(mtype, 0, headers, body).with_endian(self.endian);
+        message.fds = fds;
+        self.call(message).await
+    }
+
+    /// Send given message without waiting for any reply, see `DbusConnection::send_msg`.
+    /// Returns the serial of the sent message once it has been completely written
+    pub async fn send_msg(&self, message: Message) -> Result<u32> {
+        let serial = lock(&self.inner.state).conn.send_msg(message)?;
+        Flush { conn: self }.await?;
+        Ok(serial)
+    }
+
+    /// Send given method call and wait for its reply, see `DbusConnection::call`
+    pub async fn call(&self, message: Message) -> Result<Message> {
+        message.check_reply_expected()?;
+        let serial = {
+            let mut state = lock(&self.inner.state);
+            let serial = state.conn.send_msg(message)?;
+            // we must be registered before anyone else reads, or the reply would be discarded
+            state.waiting.insert(serial, None);
+            serial
+        };
+        Reply { conn: self, serial }.await
+    }
+
+    /// Get a stream of all messages which are not replies to our calls, such as signals.
+    /// Each stream gets a copy of every message received after it was created
+    pub fn signals(&self) -> SignalStream {
+        let mut state = lock(&self.inner.state);
+        let id = state.next_subscriber;
+        state.next_subscriber += 1;
+        state.subscribers.insert(
+            id,
+            Subscriber {
+                queue: VecDeque::new(),
+                waker: None,
+            },
+        );
+        SignalStream {
+            conn: self.clone(),
+            id,
+        }
+    }
+
+    /// Get a proxy for given destination and path,
+    /// see `Proxy::new`
+    pub fn proxy(
+        &self,
+        destination: impl TryInto<BusName, Error = DbusError>,
+        path: impl TryInto<ObjectPath, Error = DbusError>,
+    ) -> Result<AsyncProxy> {
+        Ok(AsyncProxy {
+            conn: self.clone(),
+            dest: destination.try_into()?,
+            path: path.try_into()?,
+        })
+    }
+}
+
+/// Future which completes once all the queued messages have been written
+struct Flush<'a> {
+    conn: &'a AsyncConnection,
+}
+
+impl Future for Flush<'_> {
+    type Output = Result<()>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = &self.conn.inner;
+        match lock(&inner.state).conn.flush() {
+            Ok(true) => Poll::Ready(Ok(())),
+            Ok(false) => {
+                inner.notifier.register(cx.waker(), true);
+                Poll::Pending
+            }
+            Err(e) => Poll::Ready(Err(e)),
+        }
+    }
+}
+
+/// Future which completes when the reply to message with given serial arrives
+struct Reply<'a> {
+    conn: &'a AsyncConnection,
+    serial: u32,
+}
+
+impl Future for Reply<'_> {
+    type Output = Result<Message>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        let inner = &self.conn.inner;
+        let mut wake = vec![];
+        let mut state = lock(&inner.state);
+
+        let res = (|| {
+            if let Some(msg) = state.replies.remove(&self.serial) {
+                return Some(Ok(msg));
+            }
+            // our message might not have been completely written yet
+            match state.conn.flush() {
+                Ok(true) => {}
+                Ok(false) => inner.notifier.register(cx.waker(), true),
+                Err(e) => return Some(Err(e)),
+            }
+            let read = state.read_available(&mut wake);
+            // the reply could have been read just before the connection closed
+            if let Some(msg) = state.replies.remove(&self.serial) {
+                return Some(Ok(msg));
+            }
+            read.err().map(Err)
+        })();
+
+        let poll = match res {
+            Some(res) => {
+                state.waiting.remove(&self.serial);
+                Poll::Ready(res)
+            }
+            None => {
+                state.waiting.insert(self.serial, Some(cx.waker().clone()));
+                inner.notifier.register(cx.waker(), false);
+                Poll::Pending
+            }
+        };
+        drop(state);
+        wake.into_iter().for_each(Waker::wake);
+        poll
+    }
+}
+
+impl Drop for Reply<'_> {
+    fn drop(&mut self) {
+        let mut state = lock(&self.conn.inner.state);
+        state.waiting.remove(&self.serial);
+        state.replies.remove(&self.serial);
+    }
+}
+
+/// Stream of messages which are not replies to our calls, such as signals.
+/// Use it through `StreamExt` of the futures crates, or `recv()` to get the next message
+/// without them. Once the connection closes, this gives the Disconnected signal and then ends
+pub struct SignalStream {
+    conn: AsyncConnection,
+    id: usize,
+}
+
+impl SignalStream {
+    /// Get the next message, or None once the connection has closed
+    pub fn recv(&mut self) -> Recv<'_> {
+        Recv { stream: self }
+    }
+}
+
+impl Stream for SignalStream {
+    type Item = Message;
+
+    /// Returns `Ready(None)` once the connection has closed and all messages have been taken
+    fn poll_next(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Message>> {
+        let inner = &self.conn.inner;
+        let mut wake = vec![];
+        let mut state = lock(&inner.state);
+
+        let mut msg = state
+            .subscribers
+            .get_mut(&self.id)
+            .and_then(|s| s.queue.pop_front());
+        if msg.is_none() && state.connected {
+            // errors are seen by the callers too, here we only care if the connection closed
+            let _ = state.read_available(&mut wake);
+            msg = state
+                .subscribers
+                .get_mut(&self.id)
+                .and_then(|s| s.queue.pop_front());
+        }
+
+        let poll = match msg {
+            Some(msg) => Poll::Ready(Some(msg)),
+            None if !state.connected => Poll::Ready(None),
+            None => {
+                if let Some(sub) = state.subscribers.get_mut(&self.id) {
+                    sub.waker = Some(cx.waker().clone());
+                }
+                inner.notifier.register(cx.waker(), false);
+                Poll::Pending
+            }
+        };
+        drop(state);
+        wake.into_iter().for_each(Waker::wake);
+        poll
+    }
+}
+
+impl Drop for SignalStream {
+    fn drop(&mut self) {
+        lock(&self.conn.inner.state).subscribers.remove(&self.id);
+    }
+}
+
+/// Future for the next message of a signal stream
+pub struct Recv<'a> {
+    stream: &'a mut SignalStream,
+}
+
+impl Future for Recv<'_> {
+    type Output = Option<Message>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+        Pin::new(&mut *self.stream).poll_next(cx)
+    }
+}
+
+/// Same as `Proxy`, but over an async connection
+#[derive(Clone)]
+pub struct AsyncProxy {
+    conn: AsyncConnection,
+    dest: BusName,
+    path: ObjectPath,
+}
+
+impl AsyncProxy {
+    /// Do a method call for given interface and member by sending given body,
+    /// see `Proxy::method_call`
+    pub async fn method_call<Body: DbusSerialize, Output: DbusSerialize>(
+        &self,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+        body: Option<Body>,
+    ) -> Result<Output> {
+        let call = build_method_call(
+            &self.dest,
+            &self.path,
+            interface,
+            member,
+            body,
+            self.conn.endian,
+        )?;
+        let reply = self.conn.call(call).await?;
+        parse_method_reply(reply)
+    }
+
+    /// Do a method call without waiting for its reply, see `Proxy::method_call_no_reply`
+    pub async fn method_call_no_reply<Body: DbusSerialize>(
+        &self,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+        body: Option<Body>,
+    ) -> Result<()> {
+        let call = build_method_call(
+            &self.dest,
+            &self.path,
+            interface,
+            member,
+            body,
+            self.conn.endian,
+        )?;
+        self.conn
+            .send_msg(call.with_flags(MessageFlags::NO_REPLY_EXPECTED))
+            .await?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dbus::test::{listen_abstract, serve_reversed_replies};
+    use futures_util::StreamExt;
+    use std::task::Wake;
+    use std::thread::Thread;
+
+    struct ThreadWaker(Thread);
+
+    impl Wake for ThreadWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.unpark();
+        }
+    }
+
+    /// Minimal executor, to check that we do not depend on any particular runtime
+    fn block_on<F: Future>(fut: F) -> F::Output {
+        let mut fut = std::pin::pin!(fut);
+        let waker = Waker::from(Arc::new(ThreadWaker(std::thread::current())));
+        let mut cx = Context::from_waker(&waker);
+        loop {
+            if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+                return out;
+            }
+            std::thread::park();
+        }
+    }
+
+    #[test]
+    fn test_async_connection() {
+        let name = format!("dbus-native-test-async-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = serve_reversed_replies(listener);
+            let _ = nix::unistd::close(fd);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        let conn = AsyncConnection::new(conn).unwrap();
+        let mut signals = conn.signals();
+        let proxy = conn.proxy("org.example.Test", "/org/example/Test").unwrap();
+
+        let first = proxy.method_call::<(), u32>("org.example.Test", "First", None);
+        let second = proxy.method_call::<(), u32>("org.example.Test", "Second", None);
+        let (first, second) = block_on(async {
+            let mut first = std::pin::pin!(first);
+            let mut second = std::pin::pin!(second);
+            let (mut a, mut b) = (None, None);
+            std::future::poll_fn(|cx| {
+                if a.is_none() {
+                    if let Poll::Ready(r) = first.as_mut().poll(cx) {
+                        a = Some(r);
+                    }
+                }
+                if b.is_none() {
+                    if let Poll::Ready(r) = second.as_mut().poll(cx) {
+                        b = Some(r);
+                    }
+                }
+                if a.is_some() && b.is_some() {
+                    Poll::Ready((a.take().unwrap(), b.take().unwrap()))
+                } else {
+                    Poll::Pending
+                }
+            })
+            .await
+        });
+        assert_eq!(first.unwrap(), 1);
+        assert_eq!(second.unwrap(), 2);
+
+        let signal = block_on(signals.recv()).unwrap();
+        assert_eq!(signal.preamble.mtype, MessageType::Signal);
+
+        // once the server hangs up, we get the Disconnected signal and the stream ends
+        server.join().unwrap();
+        let signal = block_on(signals.next()).unwrap();
+        assert!(matches!(
+            signal.get_header(&HeaderFieldKind::Member),
+            Some(HeaderFieldValue::String(s)) if s == "Disconnected"
+        ));
+        assert!(block_on(signals.next()).is_none());
+        assert!(!conn.is_connected());
+    }
+}
@@ -0,0 +1,832 @@
+// see https://dbus.freedesktop.org/doc/dbus-specification.html#auth-protocol
+
+use std::collections::VecDeque;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use crate::utils::{random_hex, DbusError, Result};
+
+/// Errors that can occur while authenticating a connection
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    /// Server rejected all the mechanisms we tried, contains the mechanisms server supports
+    Rejected(Vec<String>),
+    /// A mechanism could not produce its response on our side, e.g. keyring could not be read
+    MechanismFailed {
+        mechanism: &'static str,
+        reason: String,
+    },
+    /// Server sent an ERROR and did not reject the mechanism after we cancelled it
+    ServerError(String),
+    /// Server sent a reply which is not valid in the current state of authentication
+    UnexpectedReply(String),
+    /// Server sent a line which is not valid ascii
+    InvalidReply,
+    /// guid sent by the server does not match the one specified in the address
+    GuidMismatch { expected: String, found: String },
+    /// Server closed the connection before authentication completed
+    ConnectionClosed,
+    /// Client sent a command which ends the authentication on the server side,
+    /// such as BEGIN before it was authenticated
+    UnexpectedCommand(String),
+    /// Peer sent a line longer than `MAX_AUTH_LINE_LENGTH`
+    LineTooLong,
+    /// Client was rejected more than `MAX_REJECTIONS` times, so the server gave up on it
+    TooManyRejections,
+}
+
+impl From<AuthError> for DbusError {
+    fn from(err: AuthError) -> DbusError {
+        DbusError::AuthError(err)
+    }
+}
+
+/// Mechanisms which can be used by the client to authenticate
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AuthMechanism {
+    /// Authenticate using the credentials of the socket. If a uid is given, we claim to be
+    /// that uid, otherwise we send an empty response and the server takes the identity
+    /// from the socket credentials. The latter is needed in user namespaces, where
+    /// the uid we see is not the same as the one the server sees
+    External(Option<u32>),
+    /// Authenticate as given uid by proving that we can read the secret cookie
+    /// from the user's keyring, used where socket credentials are not available such as tcp
+    CookieSha1(u32),
+    /// Connect without any identity, with an optional trace string which the server
+    /// may log. This only works with peers or bus configs which allow anonymous connections
+    Anonymous(Option<String>),
+}
+
+impl AuthMechanism {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::External(_) => "EXTERNAL",
+            Self::CookieSha1(_) => "DBUS_COOKIE_SHA1",
+            Self::Anonymous(_) => "ANONYMOUS",
+        }
+    }
+
+    /// Response sent along with the AUTH command, if any
+    fn initial_response(&self) -> Option<Vec<u8>> {
+        match self {
+            Self::External(uid) => uid.map(|uid| uid.to_string().into_bytes()),
+            Self::CookieSha1(uid) => Some(uid.to_string().into_bytes()),
+            Self::Anonymous(trace) => trace.as_ref().map(|t| t.as_bytes().to_vec()),
+        }
+    }
+
+    /// What we wait for after sending the AUTH command
+    fn waiting_for(&self) -> WaitingFor {
+        match self {
+            // without initial response, server will ask for it with an empty DATA
+            Self::External(None) | Self::Anonymous(None) | Self::CookieSha1(_) => WaitingFor::Data,
+            Self::External(Some(_)) | Self::Anonymous(Some(_)) => WaitingFor::Ok,
+        }
+    }
+
+    /// Response to the DATA sent by server
+    fn respond(&self, data: &[u8], keyring_dir: &Option<PathBuf>) -> Result<Vec<u8>> {
+        match self {
+            Self::CookieSha1(_) => {
+                let keyring_dir = match keyring_dir {
+                    Some(dir) => dir.clone(),
+                    None => default_keyring_dir()?,
+                };
+                cookie_sha1_response(&keyring_dir, data)
+            }
+            // we reply to the server's request for initial response with an empty one,
+            // so the identity is taken from the socket credentials
+            Self::External(None) | Self::Anonymous(None) if data.is_empty() => Ok(vec![]),
+            Self::External(_) | Self::Anonymous(_) => Err(DbusError::ConnectionError(format!(
+                "unexpected data for {} mechanism",
+                self.name()
+            ))),
+        }
+    }
+}
+
+/// States of the client side of auth protocol, as given in the spec,
+/// i.e. what the client is waiting for from the server
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum WaitingFor {
+    Data,
+    Ok,
+    Reject,
+    AgreeUnixFd,
+}
+
+/// What the connection should do next during authentication
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SaslAction {
+    /// Send given line to the server, and pass the reply back to the client
+    Send(String),
+    /// Authentication is complete, with the given server guid
+    /// and whether the server agreed to pass fds
+    Authenticated { guid: String, unix_fd: bool },
+}
+
+/// Client side state machine of the SASL auth protocol.
+/// This only deals with lines, so the actual reading and writing on
+/// the socket is left to the connection
+pub(crate) struct SaslClient {
+    /// mechanisms we can still try, in order of preference
+    mechanisms: VecDeque<AuthMechanism>,
+    current: Option<AuthMechanism>,
+    state: WaitingFor,
+    keyring_dir: Option<PathBuf>,
+    /// the reason for failure of the last mechanism, to report if we run out of mechanisms
+    last_error: Option<AuthError>,
+    /// if we should ask the server to allow passing fds once authenticated
+    negotiate_unix_fd: bool,
+    /// guid of the server, stored while we negotiate fd passing
+    guid: Option<String>,
+}
+
+impl SaslClient {
+    pub fn new(
+        mechanisms: Vec<AuthMechanism>,
+        keyring_dir: Option<PathBuf>,
+        negotiate_unix_fd: bool,
+    ) -> Self {
+        Self {
+            mechanisms: mechanisms.into(),
+            current: None,
+            state: WaitingFor::Reject,
+            keyring_dir,
+            last_error: None,
+            negotiate_unix_fd,
+            guid: None,
+        }
+    }
+
+    /// Get the AUTH command for the first mechanism
+    pub fn start(&mut self) -> Result<SaslAction> {
+        self.next_mechanism(None)
+    }
+
+    /// Picks the next mechanism we can try, skipping the ones which
+    /// the server does not support if we know which ones it does
+    fn next_mechanism(&mut self, supported: Option<&[String]>) -> Result<SaslAction> {
+        while let Some(mechanism) = self.mechanisms.pop_front() {
+            if let Some(supported) = supported {
+                if !supported.iter().any(|m| m == mechanism.name()) {
+                    continue;
+                }
+            }
+            let line = match mechanism.initial_response() {
+                Some(r) => format!("AUTH {} {}", mechanism.name(), hex_encode(&r)),
+                None => format!("AUTH {}", mechanism.name()),
+            };
+            self.state = mechanism.waiting_for();
+            self.current = Some(mechanism);
+            return Ok(SaslAction::Send(line));
+        }
+
+        Err(match self.last_error.take() {
+            Some(e) => e.into(),
+            None => AuthError::Rejected(supported.map(|s| s.to_vec()).unwrap_or_default()).into(),
+        })
+    }
+
+    /// Handles a single line received from the server, without line ending
+    pub fn handle_line(&mut self, line: &str) -> Result<SaslAction> {
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+
+        match (self.state, command) {
+            // server either agrees or sends an error if it cannot pass fds,
+            // in both cases we are authenticated
+            (WaitingFor::AgreeUnixFd, "AGREE_UNIX_FD" | "ERROR") => {
+                Ok(SaslAction::Authenticated {
+                    // we can unwrap, as guid is always set before waiting for agreement
+                    guid: self.guid.take().unwrap(),
+                    unix_fd: command == "AGREE_UNIX_FD",
+                })
+            }
+            (WaitingFor::AgreeUnixFd, _) => {
+                Err(AuthError::UnexpectedReply(line.to_string()).into())
+            }
+            (_, "REJECTED") => {
+                let supported: Vec<_> = args.split_whitespace().map(String::from).collect();
+                self.next_mechanism(Some(&supported))
+            }
+            (WaitingFor::Data | WaitingFor::Ok, "OK") => {
+                if self.negotiate_unix_fd {
+                    self.guid = Some(args.to_string());
+                    self.state = WaitingFor::AgreeUnixFd;
+                    return Ok(SaslAction::Send("NEGOTIATE_UNIX_FD".to_string()));
+                }
+                Ok(SaslAction::Authenticated {
+                    guid: args.to_string(),
+                    unix_fd: false,
+                })
+            }
+            (WaitingFor::Data, "DATA") => {
+                // we can unwrap here as current is always set when waiting for data
+                let mechanism = self.current.as_ref().unwrap();
+                let response = hex_decode(args)
+                    .ok_or_else(|| AuthError::UnexpectedReply(line.to_string()).into())
+                    .and_then(|data| mechanism.respond(&data, &self.keyring_dir));
+                match response {
+                    Ok(r) if r.is_empty() => Ok(SaslAction::Send("DATA".to_string())),
+                    Ok(r) => Ok(SaslAction::Send(format!("DATA {}", hex_encode(&r)))),
+                    Err(e) => {
+                        // we cannot continue with this mechanism, so cancel it
+                        // and let the server reject it, so we can try the next one
+                        self.last_error = Some(AuthError::MechanismFailed {
+                            mechanism: mechanism.name(),
+                            reason: format!("{:?}", e),
+                        });
+                        self.cancel()
+                    }
+                }
+            }
+            (WaitingFor::Data | WaitingFor::Ok, "ERROR") => {
+                self.last_error = Some(AuthError::ServerError(args.to_string()));
+                self.cancel()
+            }
+            (WaitingFor::Ok, "DATA") => self.cancel(),
+            (WaitingFor::Reject, _) => Err(self
+                .last_error
+                .take()
+                .unwrap_or_else(|| AuthError::UnexpectedReply(line.to_string()))
+                .into()),
+            // spec says for any other reply, we send an error and stay in same state
+            _ => Ok(SaslAction::Send(format!(
+                "ERROR \"unexpected reply {}\"",
+                command
+            ))),
+        }
+    }
+
+    fn cancel(&mut self) -> Result<SaslAction> {
+        self.state = WaitingFor::Reject;
+        Ok(SaslAction::Send("CANCEL".to_string()))
+    }
+}
+
+/// States of the server side of auth protocol, as given in the spec,
+/// i.e. what the server is waiting for from the client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ServerWaitingFor {
+    Auth,
+    Data,
+    Begin,
+}
+
+/// What the server side connection should do next during authentication
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SaslServerAction {
+    /// Send given line to the client, and pass the next line from it back to the server
+    Send(String),
+    /// Client has sent BEGIN after authenticating, with whether fds can be passed
+    Begin { unix_fd: bool },
+}
+
+/// Longest line of the auth protocol we accept, same as the reference implementation,
+/// so a peer cannot make us buffer without bound by never ending a line
+pub(crate) const MAX_AUTH_LINE_LENGTH: usize = 16 * 1024;
+
+/// How many times a client can be rejected before the server closes the connection
+pub(crate) const MAX_REJECTIONS: u32 = 6;
+
+/// Server side state machine of the SASL auth protocol. This supports EXTERNAL,
+/// checked against the credentials of the socket, and optionally ANONYMOUS.
+/// Same as the client, this only deals with lines
+pub(crate) struct SaslServer {
+    guid: String,
+    /// uid of the peer process, as given by the socket credentials
+    peer_uid: Option<u32>,
+    /// uids which are allowed to connect using EXTERNAL
+    allowed_uids: Vec<u32>,
+    allow_anonymous: bool,
+    /// if fds can be passed over the socket at all
+    can_pass_fds: bool,
+    state: ServerWaitingFor,
+    unix_fd: bool,
+    /// how many times the client has been rejected so far
+    rejections: u32,
+}
+
+impl SaslServer {
+    pub fn new(
+        guid: String,
+        peer_uid: Option<u32>,
+        allowed_uids: Vec<u32>,
+        allow_anonymous: bool,
+        can_pass_fds: bool,
+    ) -> Self {
+        Self {
+            guid,
+            peer_uid,
+            allowed_uids,
+            allow_anonymous,
+            can_pass_fds,
+            state: ServerWaitingFor::Auth,
+            unix_fd: false,
+            rejections: 0,
+        }
+    }
+
+    /// Handles a single line received from the client, without line ending
+    pub fn handle_line(&mut self, line: &str) -> Result<SaslServerAction> {
+        let (command, args) = line.split_once(' ').unwrap_or((line, ""));
+
+        match (self.state, command) {
+            (ServerWaitingFor::Auth, "AUTH") => {
+                let (mechanism, initial) = args.split_once(' ').unwrap_or((args, ""));
+                match mechanism {
+                    "EXTERNAL" if initial.is_empty() => {
+                        // client will send its identity, possibly empty, in the DATA
+                        self.state = ServerWaitingFor::Data;
+                        Ok(SaslServerAction::Send("DATA".to_string()))
+                    }
+                    "EXTERNAL" => self.check_external(initial),
+                    // the trace string is only meant for logging, so we ignore it
+                    "ANONYMOUS" if self.allow_anonymous => Ok(self.accept()),
+                    _ => self.reject(),
+                }
+            }
+            (ServerWaitingFor::Data, "DATA") => self.check_external(args),
+            (ServerWaitingFor::Begin, "BEGIN") => Ok(SaslServerAction::Begin {
+                unix_fd: self.unix_fd,
+            }),
+            (ServerWaitingFor::Begin, "NEGOTIATE_UNIX_FD") => {
+                if self.can_pass_fds {
+                    self.unix_fd = true;
+                    Ok(SaslServerAction::Send("AGREE_UNIX_FD".to_string()))
+                } else {
+                    Ok(SaslServerAction::Send(
+                        "ERROR \"fd passing is not supported\"".to_string(),
+                    ))
+                }
+            }
+            (_, "CANCEL" | "ERROR") => self.reject(),
+            // spec says the server must close the connection in this case
+            (ServerWaitingFor::Auth | ServerWaitingFor::Data, "BEGIN") => {
+                Err(AuthError::UnexpectedCommand(line.to_string()).into())
+            }
+            // for any other command, we send an error and stay in same state
+            _ => Ok(SaslServerAction::Send(format!(
+                "ERROR \"unexpected command {}\"",
+                command
+            ))),
+        }
+    }
+
+    /// Checks the hex encoded identity sent for EXTERNAL against the socket credentials.
+    /// An empty identity means the client wants to be whoever the credentials say
+    fn check_external(&mut self, hex: &str) -> Result<SaslServerAction> {
+        let peer_uid = match self.peer_uid {
+            Some(uid) => uid,
+            None => return self.reject(),
+        };
+        let claimed = match hex_decode(hex) {
+            Some(id) if id.is_empty() => Some(peer_uid),
+            Some(id) => std::str::from_utf8(&id)
+                .ok()
+                .and_then(|id| id.parse::<u32>().ok()),
+            None => None,
+        };
+        match claimed {
+            Some(uid) if uid == peer_uid && self.allowed_uids.contains(&uid) => Ok(self.accept()),
+            _ => self.reject(),
+        }
+    }
+
+    fn accept(&mut self) -> SaslServerAction {
+        self.state = ServerWaitingFor::Begin;
+        SaslServerAction::Send(format!("OK {}", self.guid))
+    }
+
+    fn reject(&mut self) -> Result<SaslServerAction> {
+        self.rejections += 1;
+        if self.rejections > MAX_REJECTIONS {
+            return Err(AuthError::TooManyRejections.into());
+        }
+        self.state = ServerWaitingFor::Auth;
+        let mechanisms = if self.allow_anonymous {
+            "EXTERNAL ANONYMOUS"
+        } else {
+            "EXTERNAL"
+        };
+        Ok(SaslServerAction::Send(format!("REJECTED {}", mechanisms)))
+    }
+}
+
+/// Encode given bytes as lowercase hex string, which is how
+/// all the binary data is sent in the auth protocol
+pub(crate) fn hex_encode(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+/// Decode given hex string, returns None if it is not valid hex
+pub(crate) fn hex_decode(s: &str) -> Option<Vec<u8>> {
+    if s.len() % 2 != 0 {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| {
+            s.get(i..i + 2)
+                // from_str_radix allows a sign, which is not hex
+                .filter(|h| h.bytes().all(|b| b.is_ascii_hexdigit()))
+                .and_then(|h| u8::from_str_radix(h, 16).ok())
+        })
+        .collect()
+}
+
+/// Default directory containing the cookie keyrings, i.e. `~/.dbus-keyrings`
+pub(crate) fn default_keyring_dir() -> Result<PathBuf> {
+    let home = match std::env::var_os("HOME").filter(|h| !h.is_empty()) {
+        Some(h) => PathBuf::from(h),
+        None => nix::unistd::User::from_uid(nix::unistd::geteuid())?
+            .map(|u| u.dir)
+            .ok_or_else(|| {
+                DbusError::ConnectionError("cannot find home directory for keyrings".into())
+            })?,
+    };
+    Ok(home.join(".dbus-keyrings"))
+}
+
+/// Finds the cookie with given id in the keyring file of given context.
+/// Each line of the keyring is of form `<id> <creation time> <cookie>`
+fn read_cookie(keyring_dir: &Path, context: &str, id: &str) -> Result<String> {
+    // context is used as a file name, so we must make sure it cannot escape the keyring dir
+    if context.is_empty() || context.starts_with('.') || context.contains('/') {
+        return Err(DbusError::ConnectionError(format!(
+            "invalid cookie context {}",
+            context
+        )));
+    }
+
+    let mode = std::fs::metadata(keyring_dir)?.permissions().mode();
+    if mode & 0o077 != 0 {
+        return Err(DbusError::ConnectionError(format!(
+            "keyring directory {} must not be accessible by group or others",
+            keyring_dir.display()
+        )));
+    }
+
+    let keyring = std::fs::read_to_string(keyring_dir.join(context))?;
+    keyring
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.split(' ');
+            match (parts.next(), parts.next(), parts.next()) {
+                (Some(cookie_id), Some(_), Some(cookie)) if cookie_id == id => {
+                    Some(cookie.to_string())
+                }
+                _ => None,
+            }
+        })
+        .next()
+        .ok_or_else(|| {
+            DbusError::ConnectionError(format!("cookie {} not found in keyring {}", id, context))
+        })
+}
+
+/// Computes the client response for DBUS_COOKIE_SHA1 mechanism.
+/// The server data is `<context> <cookie id> <server challenge>`, and the response
+/// is `<client challenge> <hex sha1 of server_challenge:client_challenge:cookie>`
+pub(crate) fn cookie_sha1_response(keyring_dir: &Path, server_data: &[u8]) -> Result<Vec<u8>> {
+    let data = std::str::from_utf8(server_data).map_err(|_| {
+        DbusError::ConnectionError("cookie auth data from server is not valid utf-8".into())
+    })?;
+
+    let parts: Vec<_> = data.split(' ').collect();
+    let (context, id, server_challenge) = match parts.as_slice() {
+        [context, id, challenge] => (*context, *id, *challenge),
+        _ => {
+            return Err(DbusError::ConnectionError(format!(
+                "invalid cookie auth data from server : {}",
+                data
+            )))
+        }
+    };
+
+    let cookie = read_cookie(keyring_dir, context, id)?;
+    let client_challenge = random_hex(16)?;
+
+    let to_hash = format!("{}:{}:{}", server_challenge, client_challenge, cookie);
+    let hash = hex_encode(&sha1(to_hash.as_bytes()));
+
+    Ok(format!("{} {}", client_challenge, hash).into_bytes())
+}
+
+/// Plain sha1 digest, as specified in RFC 3174
+/// we only need this for cookie auth, so it is not worth pulling in a crate for it
+pub(crate) fn sha1(data: &[u8]) -> [u8; 20] {
+    let mut h: [u32; 5] = [0x67452301, 0xEFCDAB89, 0x98BADCFE, 0x10325476, 0xC3D2E1F0];
+
+    // pad the message with a 1 bit, then zeroes and finally the bit length as u64,
+    // so that total length is a multiple of 64 bytes
+    let mut msg = data.to_vec();
+    msg.push(0x80);
+    while msg.len() % 64 != 56 {
+        msg.push(0);
+    }
+    msg.extend_from_slice(&((data.len() as u64) * 8).to_be_bytes());
+
+    for chunk in msg.chunks(64) {
+        let mut w = [0u32; 80];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..80 {
+            w[i] = (w[i - 3] ^ w[i - 8] ^ w[i - 14] ^ w[i - 16]).rotate_left(1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e] = h;
+        for (i, wi) in w.iter().enumerate() {
+            let (f, k) = match i {
+                0..=19 => ((b & c) | (!b & d), 0x5A827999),
+                20..=39 => (b ^ c ^ d, 0x6ED9EBA1),
+                40..=59 => ((b & c) | (b & d) | (c & d), 0x8F1BBCDC),
+                _ => (b ^ c ^ d, 0xCA62C1D6),
+            };
+            let temp = a
+                .rotate_left(5)
+                .wrapping_add(f)
+                .wrapping_add(e)
+                .wrapping_add(k)
+                .wrapping_add(*wi);
+            e = d;
+            d = c;
+            c = b.rotate_left(30);
+            b = a;
+            a = temp;
+        }
+
+        for (hv, v) in h.iter_mut().zip([a, b, c, d, e]) {
+            *hv = hv.wrapping_add(v);
+        }
+    }
+
+    let mut ret = [0; 20];
+    for (i, v) in h.iter().enumerate() {
+        ret[i * 4..i * 4 + 4].copy_from_slice(&v.to_be_bytes());
+    }
+    ret
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn send(line: &str) -> SaslAction {
+        SaslAction::Send(line.to_string())
+    }
+
+    fn authenticated(unix_fd: bool) -> SaslAction {
+        SaslAction::Authenticated {
+            guid: "1d8a1b23e6e8c5dbb0b1b0d565a1f3f2".into(),
+            unix_fd,
+        }
+    }
+
+    #[test]
+    fn test_sasl_external() {
+        let mut client = SaslClient::new(vec![AuthMechanism::External(Some(1000))], None, false);
+        assert_eq!(client.start().unwrap(), send("AUTH EXTERNAL 31303030"));
+        assert_eq!(
+            client
+                .handle_line("OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2")
+                .unwrap(),
+            authenticated(false)
+        );
+    }
+
+    #[test]
+    fn test_sasl_negotiate_unix_fd() {
+        let mut client = SaslClient::new(vec![AuthMechanism::External(None)], None, true);
+        client.start().unwrap();
+        assert_eq!(
+            client
+                .handle_line("OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2")
+                .unwrap(),
+            send("NEGOTIATE_UNIX_FD")
+        );
+        assert_eq!(
+            client.handle_line("AGREE_UNIX_FD").unwrap(),
+            authenticated(true)
+        );
+
+        // server which cannot pass fds sends an error, which still completes auth
+        let mut client = SaslClient::new(vec![AuthMechanism::External(None)], None, true);
+        client.start().unwrap();
+        client
+            .handle_line("OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2")
+            .unwrap();
+        assert_eq!(
+            client.handle_line("ERROR \"not supported\"").unwrap(),
+            authenticated(false)
+        );
+    }
+
+    #[test]
+    fn test_sasl_external_without_uid() {
+        let mut client = SaslClient::new(vec![AuthMechanism::External(None)], None, false);
+        assert_eq!(client.start().unwrap(), send("AUTH EXTERNAL"));
+        assert_eq!(client.handle_line("DATA").unwrap(), send("DATA"));
+        assert_eq!(
+            client
+                .handle_line("OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2")
+                .unwrap(),
+            authenticated(false)
+        );
+
+        // server can also accept straight away without asking for data
+        let mut client = SaslClient::new(vec![AuthMechanism::External(None)], None, false);
+        client.start().unwrap();
+        assert_eq!(
+            client
+                .handle_line("OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2")
+                .unwrap(),
+            authenticated(false)
+        );
+    }
+
+    #[test]
+    fn test_sasl_rejected_fallback() {
+        let mut client = SaslClient::new(
+            vec![
+                AuthMechanism::External(Some(0)),
+                AuthMechanism::CookieSha1(0),
+            ],
+            Some("/nonexistent".into()),
+            false,
+        );
+        assert_eq!(client.start().unwrap(), send("AUTH EXTERNAL 30"));
+        // falls back to cookie, as server supports it
+        assert_eq!(
+            client
+                .handle_line("REJECTED DBUS_COOKIE_SHA1 ANONYMOUS")
+                .unwrap(),
+            send("AUTH DBUS_COOKIE_SHA1 30")
+        );
+        // keyring cannot be read, so we cancel
+        assert_eq!(
+            client
+                .handle_line(&format!("DATA {}", hex_encode(b"ctx 1 abcd")))
+                .unwrap(),
+            send("CANCEL")
+        );
+        // and on rejection, the local failure is reported as there is nothing left to try
+        match client.handle_line("REJECTED DBUS_COOKIE_SHA1 ANONYMOUS") {
+            Err(DbusError::AuthError(AuthError::MechanismFailed { mechanism, .. })) => {
+                assert_eq!(mechanism, "DBUS_COOKIE_SHA1")
+            }
+            v => panic!("expected mechanism failure, got {:?}", v),
+        }
+
+        // mechanisms not supported by server are skipped
+        let mut client = SaslClient::new(
+            vec![
+                AuthMechanism::External(Some(0)),
+                AuthMechanism::CookieSha1(0),
+            ],
+            None,
+            false,
+        );
+        client.start().unwrap();
+        match client.handle_line("REJECTED ANONYMOUS") {
+            Err(DbusError::AuthError(AuthError::Rejected(m))) => assert_eq!(m, vec!["ANONYMOUS"]),
+            v => panic!("expected rejection, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_sasl_anonymous() {
+        let mut client = SaslClient::new(
+            vec![
+                AuthMechanism::External(None),
+                AuthMechanism::Anonymous(Some("dbus-native".into())),
+            ],
+            None,
+            false,
+        );
+        assert_eq!(client.start().unwrap(), send("AUTH EXTERNAL"));
+        assert_eq!(
+            client.handle_line("REJECTED ANONYMOUS").unwrap(),
+            send(&format!("AUTH ANONYMOUS {}", hex_encode(b"dbus-native")))
+        );
+        assert_eq!(
+            client
+                .handle_line("OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2")
+                .unwrap(),
+            authenticated(false)
+        );
+
+        // without trace, server may ask for an initial response
+        let mut client = SaslClient::new(vec![AuthMechanism::Anonymous(None)], None, false);
+        assert_eq!(client.start().unwrap(), send("AUTH ANONYMOUS"));
+        assert_eq!(client.handle_line("DATA").unwrap(), send("DATA"));
+    }
+
+    #[test]
+    fn test_sasl_error_and_unexpected() {
+        let mut client = SaslClient::new(vec![AuthMechanism::External(Some(0))], None, false);
+        client.start().unwrap();
+        // unknown commands get an error back
+        assert_eq!(
+            client.handle_line("FOO").unwrap(),
+            send("ERROR \"unexpected reply FOO\"")
+        );
+        // server error makes us cancel
+        assert_eq!(client.handle_line("ERROR \"bad\"").unwrap(), send("CANCEL"));
+        // anything other than reject after cancel is an error
+        match client.handle_line("OK abcd") {
+            Err(DbusError::AuthError(AuthError::ServerError(e))) => assert_eq!(e, "\"bad\""),
+            v => panic!("expected server error, got {:?}", v),
+        }
+    }
+
+    #[test]
+    fn test_sasl_server() {
+        let guid = "1d8a1b23e6e8c5dbb0b1b0d565a1f3f2".to_string();
+        let ok = SaslServerAction::Send(format!("OK {}", guid));
+        let rejected = SaslServerAction::Send("REJECTED EXTERNAL".into());
+
+        let mut server = SaslServer::new(guid.clone(), Some(1000), vec![1000], false, true);
+        // claiming someone else's uid, or using a mechanism we do not allow is rejected
+        assert_eq!(server.handle_line("AUTH EXTERNAL 30").unwrap(), rejected);
+        assert_eq!(server.handle_line("AUTH ANONYMOUS").unwrap(), rejected);
+        assert_eq!(server.handle_line("AUTH EXTERNAL 31303030").unwrap(), ok);
+        assert_eq!(
+            server.handle_line("NEGOTIATE_UNIX_FD").unwrap(),
+            SaslServerAction::Send("AGREE_UNIX_FD".into())
+        );
+        assert_eq!(
+            server.handle_line("BEGIN").unwrap(),
+            SaslServerAction::Begin { unix_fd: true }
+        );
+
+        // identity can be left for the server to take from the credentials
+        let mut server = SaslServer::new(guid.clone(), Some(1000), vec![1000], true, false);
+        assert_eq!(
+            server.handle_line("AUTH EXTERNAL").unwrap(),
+            SaslServerAction::Send("DATA".into())
+        );
+        assert_eq!(server.handle_line("DATA").unwrap(), ok);
+        assert!(matches!(
+            server.handle_line("NEGOTIATE_UNIX_FD").unwrap(),
+            SaslServerAction::Send(l) if l.starts_with("ERROR")
+        ));
+        assert_eq!(
+            server.handle_line("BEGIN").unwrap(),
+            SaslServerAction::Begin { unix_fd: false }
+        );
+
+        // anonymous works only when allowed, and uids not allowed are rejected
+        let mut server = SaslServer::new(guid.clone(), Some(1001), vec![1000], true, false);
+        assert_eq!(
+            server.handle_line("AUTH EXTERNAL").unwrap(),
+            SaslServerAction::Send("DATA".into())
+        );
+        assert_eq!(
+            server.handle_line("DATA").unwrap(),
+            SaslServerAction::Send("REJECTED EXTERNAL ANONYMOUS".into())
+        );
+        assert_eq!(server.handle_line("AUTH ANONYMOUS 7472616365").unwrap(), ok);
+        assert!(matches!(
+            server.handle_line("FOO").unwrap(),
+            SaslServerAction::Send(l) if l.starts_with("ERROR")
+        ));
+
+        // BEGIN before being authenticated ends the connection
+        let mut server = SaslServer::new(guid, None, vec![1000], false, false);
+        assert_eq!(
+            server.handle_line("AUTH EXTERNAL 31303030").unwrap(),
+            rejected
+        );
+        assert!(server.handle_line("BEGIN").is_err());
+    }
+
+    #[test]
+    fn test_sha1() {
+        assert_eq!(
+            hex_encode(&sha1(b"")),
+            "da39a3ee5e6b4b0d3255bfef95601890afd80709"
+        );
+        assert_eq!(
+            hex_encode(&sha1(b"abc")),
+            "a9993e364706816aba3e25717850c26c9cd0d89d"
+        );
+        // this spans two blocks after padding
+        assert_eq!(
+            hex_encode(&sha1(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+            "84983e441c3bd26ebaae4aa1f95129e5e54670f1"
+        );
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!(hex_encode(b"1000"), "31303030");
+        assert_eq!(hex_decode("31303030").unwrap(), b"1000");
+        assert_eq!(hex_decode("3130303"), None);
+        assert_eq!(hex_decode("zz"), None);
+        assert_eq!(hex_decode("+f"), None);
+    }
+}
@@ -1,188 +1,2477 @@
+use std::collections::{HashSet, VecDeque};
+use std::ffi::OsString;
 use std::io::{IoSlice, IoSliceMut};
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
 
+use nix::poll::{poll, PollFd, PollFlags};
 use nix::sys::socket;
 
+use crate::address::{escape_value, DbusAddress, TcpFamily, Transport};
+use crate::auth::{
+    AuthError, AuthMechanism, SaslAction, SaslClient, SaslServer, SaslServerAction,
+    MAX_AUTH_LINE_LENGTH,
+};
 use crate::message::*;
+use crate::names::{BusName, ObjectPath, ValidationError};
 use crate::proxy::Proxy;
+use crate::serialize::DbusSerialize;
 use crate::utils::{DbusError, Result};
 
-const REPLY_BUF_SIZE: usize = 128; // seems good enough  tradeoff between extra size and repeated calls
+const REPLY_BUF_SIZE: usize = 4096; // we read in chunks of this size, extra bytes are kept for the next message
+const MAX_FDS_PER_READ: usize = 253; // this is SCM_MAX_FD, the most kernel allows to be sent at once
+
+/// same as the default timeout of reference implementation
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_secs(25);
+
+/// path and interface of the Disconnected and Reconnected signals, which are
+/// synthesized by the connection itself and never actually sent over the wire
+pub const LOCAL_PATH: &str = "/org/freedesktop/DBus/Local";
+pub const LOCAL_INTERFACE: &str = "org.freedesktop.DBus.Local";
+
+/// How to reconnect when the connection to the bus is lost, see `set_reconnect_policy`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReconnectPolicy {
+    /// how many times to try before giving up, None to keep trying forever
+    pub max_attempts: Option<u32>,
+    /// delay after the first attempt fails, doubled after each failed one.
+    /// The first attempt is made right away
+    pub initial_delay: Duration,
+    /// the delay is not increased beyond this
+    pub max_delay: Duration,
+}
+
+impl Default for ReconnectPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: Some(10),
+            initial_delay: Duration::from_millis(100),
+            max_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+const SESSION_BUS_ADDRESS_ENV: &str = "DBUS_SESSION_BUS_ADDRESS";
+const SYSTEM_BUS_ADDRESS_ENV: &str = "DBUS_SYSTEM_BUS_ADDRESS";
+const DEFAULT_SYSTEM_BUS_ADDRESS: &str = "unix:path=/run/dbus/system_bus_socket";
 
 /// NOTE that this is meant for a single-threaded use, and concurrent
 /// usage can cause errors, primarily because then the message received over
 /// socket can be out of order and we need to manager buffer and check with message counter
-/// which message is for which request etc etc
+/// which message is for which request etc etc.
+/// For sharing a connection between threads, see `SharedConnection`
 pub struct DbusConnection {
-    socket: i32,
+    /// closed automatically when the connection is dropped
+    socket: OwnedFd,
+    /// false once the peer has hung up
+    connected: bool,
     msg_ctr: u32,
+    /// the address out of given list to which we actually connected,
+    /// None if the connection was not made to an address, such as for pairs
+    address: Option<DbusAddress>,
+    /// if this is a direct connection to a peer, instead of to a bus
+    p2p: bool,
+    /// directory for cookie keyrings, if not the default one
+    keyring_dir: Option<PathBuf>,
+    /// bytes received, but not yet consumed as an auth line or a complete message
+    recv_buf: Vec<u8>,
+    /// guid sent by the server on successful authentication
+    server_guid: Option<String>,
+    /// if the server agreed to pass fds over this connection
+    unix_fd: bool,
+    /// fds which have been received, but not yet attached to their message
+    received_fds: VecDeque<OwnedFd>,
+    /// unique name assigned to us by the bus in reply to hello
+    unique_name: Option<String>,
+    /// messages received which were not the reply we were waiting for
+    incoming: VecDeque<Message>,
+    /// messages which have not been completely written yet, as the socket was not ready
+    outgoing: VecDeque<OutgoingMessage>,
+    /// how long to wait for a method reply, unless given for the call explicitly
+    timeout: Duration,
+    /// endian the messages we send are encoded in
+    endian: Endian,
+    /// all the addresses we were given, which are tried again when reconnecting
+    addresses: Vec<DbusAddress>,
+    /// mechanisms we authenticated with, used again when reconnecting
+    mechanisms: Option<Vec<AuthMechanism>>,
+    /// if set, we reconnect on sending over a connection which has been lost
+    reconnect: Option<ReconnectPolicy>,
+    /// match rules and requested names, which are registered again after reconnecting
+    match_rules: Vec<String>,
+    names: Vec<(String, u32)>,
+    /// serials of calls which timed out, their replies are discarded if they arrive later
+    abandoned: HashSet<u32>,
+}
+
+/// A serialized message, which may have been partially written to the socket
+struct OutgoingMessage {
+    bytes: Vec<u8>,
+    written: usize,
+    /// these are sent along with the first written byte of the message
+    fds: Vec<OwnedFd>,
+}
+
+/// Gets value of given env var using given getter, treating empty values as unset
+fn non_empty_env(env: &impl Fn(&str) -> Option<OsString>, var: &str) -> Option<OsString> {
+    env(var).filter(|v| !v.is_empty())
+}
+
+/// Gets an address from given env var, where an address which is not utf-8 is treated
+/// as unset, as it could not be a valid one anyway
+fn address_env(env: &impl Fn(&str) -> Option<OsString>, var: &str) -> Option<String> {
+    non_empty_env(env, var).and_then(|v| v.into_string().ok())
 }
 
-fn uid_to_hex_str(uid: u32) -> String {
-    let temp: Vec<_> = uid
-        .to_string()
-        .chars()
-        .map(|c| format!("{:x}", c as u8))
-        .collect();
-    temp.join("")
+/// Finds the session bus address from the env, as given by the getter, falling back to
+/// the bus socket in xdg runtime dir if the env var is not set
+fn session_bus_address(env: impl Fn(&str) -> Option<OsString>) -> Result<String> {
+    if let Some(addr) = address_env(&env, SESSION_BUS_ADDRESS_ENV) {
+        return Ok(addr);
+    }
+    match non_empty_env(&env, "XDG_RUNTIME_DIR") {
+        Some(dir) => {
+            let path = Path::new(&dir).join("bus");
+            Ok(format!(
+                "unix:path={}",
+                escape_value(path.as_os_str().as_bytes())
+            ))
+        }
+        None => Err(DbusError::ConnectionError(format!(
+            "cannot find session bus : neither {} nor XDG_RUNTIME_DIR is set",
+            SESSION_BUS_ADDRESS_ENV
+        ))),
+    }
+}
+
+/// Finds the system bus address from the env, as given by the getter,
+/// falling back to the default system bus socket
+fn system_bus_address(env: impl Fn(&str) -> Option<OsString>) -> String {
+    address_env(&env, SYSTEM_BUS_ADDRESS_ENV)
+        .unwrap_or_else(|| DEFAULT_SYSTEM_BUS_ADDRESS.to_string())
+}
+
+/// Connects to first of the resolved addresses of given host which accepts the connection
+fn connect_tcp(host: &str, port: u16, family: Option<TcpFamily>) -> Result<OwnedFd> {
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    let mut last_err = None;
+    for addr in (host, port).to_socket_addrs()? {
+        match family {
+            Some(TcpFamily::Ipv4) if !addr.is_ipv4() => continue,
+            Some(TcpFamily::Ipv6) if !addr.is_ipv6() => continue,
+            _ => {}
+        }
+        match TcpStream::connect(addr) {
+            Ok(stream) => return Ok(stream.into()),
+            Err(e) => last_err = Some(e),
+        }
+    }
+    Err(match last_err {
+        Some(e) => e.into(),
+        None => DbusError::ConnectionError(format!(
+            "could not resolve {}:{} for the address family",
+            host, port
+        )),
+    })
+}
+
+/// Creates a socket and connects it to given address
+fn connect_to(address: &DbusAddress) -> Result<OwnedFd> {
+    if let Transport::Tcp { host, port, family } = &address.transport {
+        return connect_tcp(host, *port, *family);
+    }
+
+    let socket = socket::socket(
+        socket::AddressFamily::Unix,
+        socket::SockType::Stream,
+        socket::SockFlag::empty(),
+        None,
+    )?;
+    // this is safe as we have just created the socket, and this makes sure it
+    // is closed if we cannot connect
+    let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+    let raw = socket.as_raw_fd();
+
+    let res = match &address.transport {
+        Transport::UnixPath(path) => {
+            socket::UnixAddr::new(path).and_then(|addr| socket::connect(raw, &addr))
+        }
+        Transport::UnixAbstract(name) => {
+            socket::UnixAddr::new_abstract(name).and_then(|addr| socket::connect(raw, &addr))
+        }
+        Transport::Tcp { .. } => unreachable!("tcp addresses are connected separately"),
+        Transport::UnixTmpdir(_) => {
+            return Err(DbusError::ConnectionError(format!(
+                "cannot connect to {} : tmpdir addresses can only be listened on",
+                address
+            )));
+        }
+    };
+
+    res?;
+    Ok(socket)
 }
 
 impl DbusConnection {
-    /// Open a new dbus connection to given address
+    /// Open a new dbus connection to given address, such as
+    /// `unix:path=/run/user/1000/bus`. If multiple `;` separated addresses are
+    /// given, they are tried in order and the first one which connects is used.
     /// Note that this does not authenticate the connection,
     /// that must be done by calling `authenticate()` later
     pub fn new(addr: &str) -> Result<Self> {
-        let socket = socket::socket(
+        let addresses = DbusAddress::parse_list(addr)?;
+
+        let mut last_err = None;
+        for address in &addresses {
+            match connect_to(address) {
+                Ok(socket) => {
+                    let mut conn = Self::from_socket(socket, Some(address.clone()));
+                    conn.addresses = addresses;
+                    return Ok(conn);
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        // parse_list never returns an empty list, so we will always have some error here
+        Err(last_err.unwrap_or_else(|| DbusError::ConnectionError("no address given".into())))
+    }
+
+    /// Creates an unauthenticated connection over given connected socket
+    pub(crate) fn from_socket(socket: OwnedFd, address: Option<DbusAddress>) -> Self {
+        Self {
+            socket,
+            connected: true,
+            msg_ctr: 0,
+            address,
+            p2p: false,
+            keyring_dir: None,
+            recv_buf: Vec::new(),
+            server_guid: None,
+            unix_fd: false,
+            received_fds: VecDeque::new(),
+            unique_name: None,
+            incoming: VecDeque::new(),
+            outgoing: VecDeque::new(),
+            timeout: DEFAULT_TIMEOUT,
+            endian: Endian::Little,
+            abandoned: HashSet::new(),
+            addresses: Vec::new(),
+            mechanisms: None,
+            reconnect: None,
+            match_rules: Vec::new(),
+            names: Vec::new(),
+        }
+    }
+
+    /// Create a connection over an already connected socket, such as one inherited
+    /// from the parent process or passed by systemd. The connection is not authenticated,
+    /// that must be done by calling `authenticate()` later. The fd is marked close-on-exec,
+    /// so it is not inherited any further
+    pub fn from_fd(fd: OwnedFd) -> Result<Self> {
+        let socket_type = socket::getsockopt(fd.as_raw_fd(), socket::sockopt::SockType)
+            .map_err(|e| DbusError::ConnectionError(format!("fd is not a socket : {}", e)))?;
+        if socket_type != socket::SockType::Stream {
+            return Err(DbusError::ConnectionError(format!(
+                "fd is not a stream socket, but {:?}",
+                socket_type
+            )));
+        }
+        nix::fcntl::fcntl(
+            fd.as_raw_fd(),
+            nix::fcntl::FcntlArg::F_SETFD(nix::fcntl::FdFlag::FD_CLOEXEC),
+        )?;
+        Ok(Self::from_socket(fd, None))
+    }
+
+    /// Create a connection over a socket which has already been authenticated, including
+    /// sending the BEGIN, such as by the parent process. As we cannot know what was
+    /// negotiated, the caller must tell if fd passing was agreed to. If this is
+    /// a connection to a bus, the Hello must have been done as well, but as its reply
+    /// was not seen by us, `unique_name()` will be None
+    pub fn from_authenticated_fd(fd: OwnedFd, unix_fd: bool) -> Result<Self> {
+        let mut conn = Self::from_fd(fd)?;
+        conn.unix_fd = unix_fd && conn.is_unix_socket();
+        Ok(conn)
+    }
+
+    /// Create a pair of connections connected to each other, for use within the process.
+    /// These are peer-to-peer connections which need no authentication,
+    /// and can pass fds to each other
+    pub fn pair() -> Result<(Self, Self)> {
+        let (a, b) = socket::socketpair(
             socket::AddressFamily::Unix,
             socket::SockType::Stream,
-            socket::SockFlag::empty(),
             None,
+            socket::SockFlag::SOCK_CLOEXEC,
         )?;
+        // this is safe as we have just created these
+        let (a, b) = unsafe { (OwnedFd::from_raw_fd(a), OwnedFd::from_raw_fd(b)) };
+        let ready = |socket| {
+            let mut conn = Self::from_socket(socket, None);
+            conn.p2p = true;
+            conn.unix_fd = true;
+            conn
+        };
+        Ok((ready(a), ready(b)))
+    }
 
-        let addr = socket::UnixAddr::new(addr)?;
-        socket::connect(socket, &addr)?;
-        Ok(Self { socket, msg_ctr: 0 })
+    /// Connect to the session bus and authenticate using the socket credentials.
+    /// The address is taken from `DBUS_SESSION_BUS_ADDRESS`, falling back
+    /// to `$XDG_RUNTIME_DIR/bus` if that is not set
+    pub fn session() -> Result<Self> {
+        Self::connect_and_authenticate(&session_bus_address(|var| std::env::var_os(var))?)
     }
 
-    /// Authenticates with dbus using given uid via external strategy
-    /// Must be called on any connection before doing any other communication
-    pub fn authenticate(&mut self, uid: u32) -> Result<()> {
-        let mut buf = [0; 64];
+    /// Connect to the system bus and authenticate using the socket credentials.
+    /// The address is taken from `DBUS_SYSTEM_BUS_ADDRESS`, falling back
+    /// to `/run/dbus/system_bus_socket` if that is not set
+    pub fn system() -> Result<Self> {
+        Self::connect_and_authenticate(&system_bus_address(|var| std::env::var_os(var)))
+    }
 
-        // dbus connection always start with a 0 byte sent as first thing
-        socket::send(self.socket, &[b'\0'], socket::MsgFlags::empty())?;
+    fn connect_and_authenticate(addr: &str) -> Result<Self> {
+        let mut conn = Self::new(addr)?;
+        // we let the bus take our identity from the socket credentials, as the uid
+        // we see can differ from what the bus sees, such as under sudo or in user namespaces
+        conn.authenticate(None)?;
+        Ok(conn)
+    }
 
-        let msg = format!("AUTH EXTERNAL {}\r\n", uid_to_hex_str(uid));
+    /// Get the default timeout for method calls on this connection
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
 
-        // then we send our auth with uid
-        socket::send(self.socket, msg.as_bytes(), socket::MsgFlags::empty())?;
+    /// Set the default timeout for method calls on this connection,
+    /// which is 25 seconds unless changed
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
+    /// Get the endian the messages sent over this connection are encoded in
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
 
-        // we get the reply and check if all wne well or not
-        socket::recv(self.socket, &mut buf, socket::MsgFlags::empty())?;
+    /// Set the endian to encode the messages sent over this connection in, which is
+    /// little endian unless changed. The body given to `send` must be serialized in the same
+    /// endian, which the proxies take care of. Received messages are decoded in whichever
+    /// endian the peer sent them in
+    pub fn set_endian(&mut self, endian: Endian) {
+        self.endian = endian;
+    }
 
-        let reply: Vec<u8> = buf.iter().filter(|v| **v != 0).copied().collect();
+    /// Get the address this connection is connected to,
+    /// None if it was not made by connecting to an address
+    pub fn address(&self) -> Option<&DbusAddress> {
+        self.address.as_ref()
+    }
 
-        // we can do this unsafe as we know dbus communication is always ascii
-        let reply = unsafe { String::from_utf8_unchecked(reply) };
+    /// Set if this is a direct connection to a peer, such as systemd's private socket,
+    /// instead of a connection to a bus. This must be set before authenticating, as peers
+    /// are not sent the Hello call, and messages to them do not get the bus-only headers
+    pub fn set_peer_to_peer(&mut self, p2p: bool) {
+        self.p2p = p2p;
+    }
 
-        if !reply.starts_with("OK") {
-            return Err(DbusError::ConnectionError(format!(
-                "Authentication failed, got message : {}",
-                reply
-            )));
+    /// Check if this is a direct connection to a peer, instead of to a bus
+    pub fn is_peer_to_peer(&self) -> bool {
+        self.p2p
+    }
+
+    /// Set the directory containing cookie keyrings, used for DBUS_COOKIE_SHA1
+    /// authentication over tcp. This defaults to `~/.dbus-keyrings`
+    pub fn set_keyring_dir(&mut self, dir: impl Into<PathBuf>) {
+        self.keyring_dir = Some(dir.into());
+    }
+
+    /// Get the guid of the server, this is known only after authentication
+    pub fn server_guid(&self) -> Option<&str> {
+        self.server_guid.as_deref()
+    }
+
+    /// Get the unique name assigned to this connection by the bus, such as `:1.2072`.
+    /// This is known only after authentication
+    pub fn unique_name(&self) -> Option<&str> {
+        self.unique_name.as_deref()
+    }
+
+    /// Check if fds can be passed over this connection, this is known only after authentication
+    pub fn unix_fd_enabled(&self) -> bool {
+        self.unix_fd
+    }
+
+    /// Check if the connection is still open, this becomes false once the peer hangs up
+    pub fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    /// Close the connection. This is the same as dropping it,
+    /// except that any error in closing the socket is returned
+    pub fn close(self) -> Result<()> {
+        nix::unistd::close(self.socket.into_raw_fd())?;
+        Ok(())
+    }
+
+    /// Creates a second connection over a duplicate of the socket, meant only for reading
+    /// from another thread while this one is used for sending. Anything which has
+    /// already been received is moved over to the new connection
+    pub(crate) fn split_reader(&mut self) -> Result<DbusConnection> {
+        Ok(DbusConnection {
+            socket: self.socket.try_clone()?,
+            connected: self.connected,
+            msg_ctr: 0,
+            address: self.address.clone(),
+            p2p: self.p2p,
+            keyring_dir: None,
+            recv_buf: std::mem::take(&mut self.recv_buf),
+            server_guid: self.server_guid.clone(),
+            unix_fd: self.unix_fd,
+            received_fds: std::mem::take(&mut self.received_fds),
+            unique_name: self.unique_name.clone(),
+            incoming: std::mem::take(&mut self.incoming),
+            outgoing: VecDeque::new(),
+            timeout: self.timeout,
+            endian: self.endian,
+            abandoned: std::mem::take(&mut self.abandoned),
+            addresses: Vec::new(),
+            mechanisms: None,
+            reconnect: None,
+            match_rules: Vec::new(),
+            names: Vec::new(),
+        })
+    }
+
+    /// Marks the connection as disconnected and queues the Disconnected signal,
+    /// as the reference implementation does. Returns the error to give to the caller
+    fn disconnect(&mut self) -> DbusError {
+        if self.connected {
+            self.connected = false;
+            self.queue_local_signal("Disconnected", ());
         }
+        DbusError::Disconnected
+    }
+
+    /// Queues a signal synthesized by the connection itself, with given member and body
+    fn queue_local_signal<Body: DbusSerialize>(&mut self, member: &str, body: Body) {
+        // this is never sent, so it does not need a proper serial. The names we use
+        // are valid and the bodies have no fds, so there is nothing which could fail
+        let signal =
+            Message::signal(LOCAL_PATH, LOCAL_INTERFACE, member).and_then(|s| s.with_body(&body));
+        if let Ok(signal) = signal {
+            self.incoming.push_back(signal);
+        }
+    }
+
+    /// Set the policy for reconnecting once the connection to the bus is lost, which is
+    /// not done by default. When set, we reconnect on sending over a connection which was
+    /// lost, authenticating again and restoring the match rules and names
+    /// registered through `add_match` and `request_name`. Calls which were waiting for
+    /// a reply when the connection was lost still fail with `DbusError::Disconnected`,
+    /// as we cannot know if they were done or not. As the unique name changes on
+    /// reconnecting, a `Reconnected` signal is queued on `LOCAL_INTERFACE` with the old
+    /// and new names. If the bus refuses to give us one of the names again, the new
+    /// connection is kept, and a `RequestNameFailed` signal is queued with the name
+    /// and the error for it
+    pub fn set_reconnect_policy(&mut self, policy: Option<ReconnectPolicy>) {
+        self.reconnect = policy;
+    }
+
+    /// Reconnect to the bus now, using the reconnect policy if one is set, or the default one
+    pub fn reconnect(&mut self) -> Result<()> {
+        let policy = self.reconnect.clone().unwrap_or_default();
+        let mechanisms = self.mechanisms.clone().ok_or_else(|| {
+            DbusError::ConnectionError(
+                "cannot reconnect : connection has not been authenticated".into(),
+            )
+        })?;
+        if self.addresses.is_empty() {
+            return Err(DbusError::ConnectionError(
+                "cannot reconnect : connection was not made to an address".into(),
+            ));
+        }
+
+        let mut delay = policy.initial_delay;
+        let mut attempt = 0;
+        loop {
+            attempt += 1;
+            match self.try_reconnect(&mechanisms) {
+                Ok(()) => return Ok(()),
+                Err(e) if policy.max_attempts.is_some_and(|max| attempt >= max) => return Err(e),
+                Err(_) => {}
+            }
+            // whatever we were connected to needs some time to come back
+            std::thread::sleep(delay);
+            delay = (delay * 2).min(policy.max_delay);
+        }
+    }
+
+    fn try_reconnect(&mut self, mechanisms: &[AuthMechanism]) -> Result<()> {
+        let mut conn = Self::new(&self.addresses_string())?;
+        conn.keyring_dir = self.keyring_dir.clone();
+        conn.timeout = self.timeout;
+        conn.endian = self.endian;
+        conn.p2p = self.p2p;
+        conn.msg_ctr = self.msg_ctr;
+        conn.authenticate_with(mechanisms.to_vec())?;
+
+        for rule in &self.match_rules {
+            conn.call_bus::<_, ()>("AddMatch", Some(rule.clone()))?;
+        }
+        let mut failed_names = vec![];
+        for (name, flags) in &self.names {
+            match conn.call_bus::<_, u32>("RequestName", Some((name.clone(), *flags))) {
+                Ok(_) => {}
+                // the bus refusing the name does not make the connection any less usable
+                Err(DbusError::MethodError { message, .. }) => {
+                    failed_names.push((name.clone(), message))
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        // authentication needs blocking mode, so this is restored only now
+        if self.is_nonblocking()? {
+            conn.set_nonblocking(true)?;
+        }
+
+        // restore all the settings, and keep the messages received before
+        // the connection was lost in front of the new ones
+        conn.reconnect = self.reconnect.take();
+        conn.match_rules = std::mem::take(&mut self.match_rules);
+        conn.names = std::mem::take(&mut self.names);
+        let mut incoming = std::mem::take(&mut self.incoming);
+        incoming.append(&mut conn.incoming);
+        conn.incoming = incoming;
+
+        let old_name = self.unique_name.take().unwrap_or_default();
+        let new_name = conn.unique_name.clone().unwrap_or_default();
+        *self = conn;
+        self.queue_local_signal("Reconnected", (old_name, new_name));
+        for failed in failed_names {
+            self.queue_local_signal("RequestNameFailed", failed);
+        }
+        Ok(())
+    }
+
+    /// All the addresses we were given, in the `;` separated form
+    fn addresses_string(&self) -> String {
+        self.addresses
+            .iter()
+            .map(|a| a.to_string())
+            .collect::<Vec<_>>()
+            .join(";")
+    }
+
+    /// Call a method of the bus itself
+    fn call_bus<Body: DbusSerialize, Output: DbusSerialize>(
+        &mut self,
+        member: &str,
+        body: Option<Body>,
+    ) -> Result<Output> {
+        self.proxy("org.freedesktop.DBus", "/org/freedesktop/DBus")?
+            .method_call("org.freedesktop.DBus", member, body)
+    }
+
+    /// Add a match rule on the bus, to receive the signals matching it, such as
+    /// `type='signal',interface='org.freedesktop.systemd1.Manager'`
+    pub fn add_match(&mut self, rule: &str) -> Result<()> {
+        self.call_bus::<_, ()>("AddMatch", Some(rule.to_string()))?;
+        self.match_rules.push(rule.to_string());
+        Ok(())
+    }
+
+    /// Remove a match rule added with `add_match`
+    pub fn remove_match(&mut self, rule: &str) -> Result<()> {
+        self.call_bus::<_, ()>("RemoveMatch", Some(rule.to_string()))?;
+        if let Some(pos) = self.match_rules.iter().position(|r| r == rule) {
+            self.match_rules.remove(pos);
+        }
+        Ok(())
+    }
+
+    /// Request a well-known name on the bus with given flags, returning the reply code
+    /// of the bus, such as 1 if we are now the primary owner of the name
+    pub fn request_name(&mut self, name: &str, flags: u32) -> Result<u32> {
+        let reply = self.call_bus::<_, u32>("RequestName", Some((name.to_string(), flags)))?;
+        if !self.names.iter().any(|(n, _)| n == name) {
+            self.names.push((name.to_string(), flags));
+        }
+        Ok(reply)
+    }
+
+    /// Release a well-known name requested with `request_name`, returning the reply
+    /// code of the bus, such as 1 if the name was released
+    pub fn release_name(&mut self, name: &str) -> Result<u32> {
+        let reply = self.call_bus::<_, u32>("ReleaseName", Some(name.to_string()))?;
+        self.names.retain(|(n, _)| n != name);
+        Ok(reply)
+    }
+
+    /// Sends a single line of the auth protocol, adding the line ending
+    fn send_auth_line(&mut self, line: &str) -> Result<()> {
+        let msg = format!("{}\r\n", line);
+        socket::send(
+            self.socket.as_raw_fd(),
+            msg.as_bytes(),
+            socket::MsgFlags::MSG_NOSIGNAL,
+        )?;
+        Ok(())
+    }
+
+    /// Reads a single line of the auth protocol, without the line ending.
+    /// A line can arrive split over multiple reads, or multiple lines can
+    /// arrive in a single read, so we keep the extra bytes around for next line.
+    /// If a deadline is given, this fails with `DbusError::Timeout` once it passes
+    fn read_auth_line(&mut self, deadline: Option<Instant>) -> Result<String> {
+        loop {
+            if let Some(pos) = self.recv_buf.windows(2).position(|w| w == b"\r\n") {
+                let line: Vec<u8> = self.recv_buf.drain(..pos + 2).take(pos).collect();
+                // auth protocol is always ascii
+                if !line.is_ascii() {
+                    return Err(AuthError::InvalidReply.into());
+                }
+                // we can unwrap, as ascii is always valid utf-8
+                return Ok(String::from_utf8(line).unwrap());
+            }
+            if self.recv_buf.len() > MAX_AUTH_LINE_LENGTH {
+                return Err(AuthError::LineTooLong.into());
+            }
+
+            match self.read_more()? {
+                Some(0) => return Err(AuthError::ConnectionClosed.into()),
+                Some(_) => {}
+                None => {
+                    if !self.wait_ready(deadline, PollFlags::POLLIN)? {
+                        return Err(DbusError::Timeout(
+                            "authentication did not complete in time".into(),
+                        ));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Authenticates with dbus, optionally claiming given uid. This tries the external strategy
+    /// first, falling back to DBUS_COOKIE_SHA1 strategy if the server rejects it, which is the case
+    /// for tcp as it does not have socket credentials, and finally to ANONYMOUS if the server
+    /// allows it. If no uid is given, the server takes our identity from the socket credentials,
+    /// which is what should be used in most cases.
+    /// Must be called on any connection before doing any other communication
+    pub fn authenticate(&mut self, uid: Option<u32>) -> Result<()> {
+        // cookie auth always needs an identity, so we use our effective uid if none is given
+        let cookie_uid = uid.unwrap_or_else(|| nix::unistd::geteuid().as_raw());
+        self.authenticate_with(vec![
+            AuthMechanism::External(uid),
+            AuthMechanism::CookieSha1(cookie_uid),
+            AuthMechanism::Anonymous(None),
+        ])
+    }
+
+    /// Authenticates with dbus trying given mechanisms in order, until the server accepts one
+    /// Must be called on any connection before doing any other communication
+    pub fn authenticate_with(&mut self, mechanisms: Vec<AuthMechanism>) -> Result<()> {
+        self.mechanisms = Some(mechanisms.clone());
+        // dbus connection always start with a 0 byte sent as first thing
+        socket::send(
+            self.socket.as_raw_fd(),
+            b"\0",
+            socket::MsgFlags::MSG_NOSIGNAL,
+        )?;
+
+        // fds can only be passed over unix sockets
+        let negotiate_unix_fd = self.is_unix_socket();
+        let mut client = SaslClient::new(mechanisms, self.keyring_dir.clone(), negotiate_unix_fd);
+        let mut action = client.start()?;
+
+        let guid = loop {
+            match action {
+                SaslAction::Send(line) => {
+                    self.send_auth_line(&line)?;
+                    let reply = self.read_auth_line(None)?;
+                    action = client.handle_line(&reply)?;
+                }
+                SaslAction::Authenticated { guid, unix_fd } => {
+                    self.unix_fd = unix_fd;
+                    break guid;
+                }
+            }
+        };
+
+        if let Some(expected) = self.address.as_ref().and_then(|a| a.guid.as_ref()) {
+            if *expected != guid {
+                return Err(AuthError::GuidMismatch {
+                    expected: expected.clone(),
+                    found: guid,
+                }
+                .into());
+            }
+        }
+        self.server_guid = Some(guid);
 
         // we must send the BEGIN before starting any actual communication
-        // we can also send AGREE_UNIX_FD before this if we need to deal with sending/receiving
-        // fds over the connection, but because youki doesn't need it, we can skip that
         socket::send(
-            self.socket,
+            self.socket.as_raw_fd(),
             "BEGIN\r\n".as_bytes(),
-            socket::MsgFlags::empty(),
+            socket::MsgFlags::MSG_NOSIGNAL,
         )?;
 
+        // peers have no names to allocate, and do not know the Hello call
+        if self.p2p {
+            return Ok(());
+        }
+
         // First thing any dbus client must do after authentication
         // is to do a hello method call, in order to get a name allocated
         // if we do any other method call, the connection iis assumed to be
         // invalid and auto disconnected
-        let headers = vec![
-            Header {
-                kind: HeaderFieldKind::Path,
-                value: HeaderFieldValue::String("/org/freedesktop/DBus".to_string()),
-            },
-            Header {
-                kind: HeaderFieldKind::Destination,
-                value: HeaderFieldValue::String("org.freedesktop.DBus".to_string()),
-            },
-            Header {
-                kind: HeaderFieldKind::Interface,
-                value: HeaderFieldValue::String("org.freedesktop.DBus".to_string()),
-            },
-            Header {
-                kind: HeaderFieldKind::Member,
-                value: HeaderFieldValue::String("Hello".to_string()),
-            },
-        ];
+        // The reply contains the unique name allocated to us, such as :1.2072
+        let unique_name = self
+            .proxy("org.freedesktop.DBus", "/org/freedesktop/DBus")?
+            .method_call::<(), String>("org.freedesktop.DBus", "Hello", None)?;
+        self.unique_name = Some(unique_name);
+
+        Ok(())
+    }
+
+    /// Does the server side of authentication for a client which has just connected,
+    /// with given guid, failing if it is not done by the deadline.
+    /// After this, the connection is used as a peer-to-peer one
+    pub(crate) fn accept_auth(
+        &mut self,
+        guid: String,
+        mut sasl: SaslServer,
+        deadline: Instant,
+    ) -> Result<()> {
+        // client starts with a 0 byte, which we expect to be followed by the first command
+        let mut line = self.read_auth_line(Some(deadline))?;
+        if !line.starts_with('\0') {
+            return Err(AuthError::UnexpectedCommand(line).into());
+        }
+        line.remove(0);
 
-        self.send_message(MessageType::MethodCall, headers, vec![])?;
+        loop {
+            match sasl.handle_line(&line)? {
+                SaslServerAction::Send(reply) => {
+                    self.send_auth_line(&reply)?;
+                    line = self.read_auth_line(Some(deadline))?;
+                }
+                SaslServerAction::Begin { unix_fd } => {
+                    self.unix_fd = unix_fd;
+                    break;
+                }
+            }
+        }
 
+        self.server_guid = Some(guid);
+        self.p2p = true;
         Ok(())
     }
 
-    /// Helper function to get complete message in chunks
-    /// over the socket. This will loop and collect all of the message
-    /// chunks into a single vector
-    fn receive_complete_response(&mut self) -> Result<Vec<u8>> {
-        let mut ret = Vec::with_capacity(512);
+    /// Check if the socket is a unix socket, as only those can pass fds
+    fn is_unix_socket(&self) -> bool {
+        socket::getsockname::<socket::SockaddrStorage>(self.socket.as_raw_fd())
+            .map(|addr| addr.as_unix_addr().is_some())
+            .unwrap_or(false)
+    }
+
+    /// Reads whatever is available on the socket into the receive buffer, without blocking.
+    /// Any fds sent along are collected as well. Returns the number of bytes read,
+    /// which is 0 if the peer has closed the connection, or None if nothing was available
+    fn read_more(&mut self) -> Result<Option<usize>> {
+        let mut reply: [u8; REPLY_BUF_SIZE] = [0_u8; REPLY_BUF_SIZE];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; MAX_FDS_PER_READ]);
+        let reply_buffer = IoSliceMut::new(&mut reply[0..]);
+        let reply_rcvd = match socket::recvmsg::<()>(
+            self.socket.as_raw_fd(),
+            &mut [reply_buffer],
+            Some(&mut cmsg_buf),
+            socket::MsgFlags::MSG_CMSG_CLOEXEC | socket::MsgFlags::MSG_DONTWAIT,
+        ) {
+            Ok(r) => r,
+            Err(nix::errno::Errno::EAGAIN | nix::errno::Errno::EINTR) => return Ok(None),
+            // reset is just the peer hanging up without a clean shutdown
+            Err(nix::errno::Errno::ECONNRESET) => return Ok(Some(0)),
+            Err(e) => return Err(e.into()),
+        };
+
+        for cmsg in reply_rcvd.cmsgs() {
+            if let socket::ControlMessageOwned::ScmRights(fds) = cmsg {
+                // this is safe, as the kernel has just given us these fds,
+                // so nothing else can own them
+                let fds = fds
+                    .into_iter()
+                    .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) });
+                self.received_fds.extend(fds);
+            }
+        }
+
+        let received_byte_count = reply_rcvd.bytes;
+        self.recv_buf
+            .extend_from_slice(&reply[0..received_byte_count]);
+        Ok(Some(received_byte_count))
+    }
+
+    /// Takes the next message out of the receive buffer, if it has been completely received.
+    /// The length of message is known from its fixed header, so a message can be split
+    /// across any number of reads, and a read can contain any number of messages
+    fn take_buffered_message(&mut self) -> Result<Option<Message>> {
+        let length = match Message::total_length(&self.recv_buf) {
+            // we cannot skip over such a message without reading all of it,
+            // so the only thing we can do is to stop reading from the peer
+            Some(l) if l > MAX_MESSAGE_LENGTH => {
+                self.recv_buf.clear();
+                self.disconnect();
+                return Err(ValidationError::MessageTooLong(l).into());
+            }
+            Some(l) if self.recv_buf.len() >= l => l,
+            _ => return Ok(None),
+        };
+
+        let bytes: Vec<u8> = self.recv_buf.drain(..length).collect();
+        let mut ctr = 0;
+        let mut msg = match Message::deserialize(&bytes, &mut ctr) {
+            Ok(msg) => msg,
+            Err(e) => {
+                // the fds sent with this message must not be left for the next one to take.
+                // If we cannot tell how many there were, none of the queued ones can be trusted
+                match Message::declared_fds(&bytes) {
+                    Some(n) => {
+                        let n = n.min(self.received_fds.len());
+                        self.received_fds.drain(..n);
+                    }
+                    None => self.received_fds.clear(),
+                }
+                return Err(e);
+            }
+        };
+        self.attach_fds(&mut msg)?;
+        Ok(Some(msg))
+    }
+
+    /// Waits until the socket is ready for any of given events, such as having some data
+    /// to read, or the deadline passes. Returns false if the deadline passed before that.
+    /// If no deadline is given, this waits indefinitely
+    fn wait_ready(&self, deadline: Option<Instant>, events: PollFlags) -> Result<bool> {
         loop {
-            let mut reply: [u8; REPLY_BUF_SIZE] = [0_u8; REPLY_BUF_SIZE];
-            let reply_buffer = IoSliceMut::new(&mut reply[0..]);
-            let reply_rcvd = socket::recvmsg::<()>(
-                self.socket,
-                &mut [reply_buffer],
-                None,
-                socket::MsgFlags::empty(),
-            )?;
+            let timeout_ms = match deadline {
+                None => -1,
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Ok(false);
+                    }
+                    // round up, so we do not wake up just before the deadline and spin
+                    let ms = remaining.as_nanos().div_ceil(1_000_000);
+                    ms.min(i32::MAX as u128) as i32
+                }
+            };
+            let mut fds = [PollFd::new(self.socket.as_raw_fd(), events)];
+            match poll(&mut fds, timeout_ms) {
+                Ok(0) => continue, // the deadline is checked again at the top
+                Ok(_) => return Ok(true),
+                Err(nix::errno::Errno::EINTR) => continue,
+                Err(e) => return Err(e.into()),
+            }
+        }
+    }
 
-            let received_byte_count = reply_rcvd.bytes;
+    /// Reads the next complete message, blocking until it is received
+    /// or the deadline passes, in which case None is returned
+    pub(crate) fn read_message(&mut self, deadline: Option<Instant>) -> Result<Option<Message>> {
+        loop {
+            if let Some(msg) = self.take_buffered_message()? {
+                return Ok(Some(msg));
+            }
+            if !self.connected {
+                return Err(DbusError::Disconnected);
+            }
+            match self.read_more()? {
+                // reconnecting waits between attempts, so it is not done while reading
+                Some(0) => return Err(self.disconnect()),
+                Some(_) => {}
+                None => {
+                    if !self.wait_ready(deadline, PollFlags::POLLIN)? {
+                        return Ok(None);
+                    }
+                }
+            }
+        }
+    }
 
-            ret.extend_from_slice(&reply[0..received_byte_count]);
+    /// Reads the next complete message if it has already been received, without blocking
+    pub(crate) fn try_read_message(&mut self) -> Result<Option<Message>> {
+        // the deadline has passed right away, so this will not wait for the socket
+        self.read_message(Some(Instant::now()))
+    }
+
+    /// Attaches the received fds to the message, according to its UnixFd header
+    fn attach_fds(&mut self, msg: &mut Message) -> Result<()> {
+        let fd_count = match msg.get_header(&HeaderFieldKind::UnixFd) {
+            Some(HeaderFieldValue::U32(n)) => *n as usize,
+            _ => 0,
+        };
+
+        if fd_count > self.received_fds.len() {
+            let received = self.received_fds.len();
+            // these were sent with this message, so are not for the next one
+            self.received_fds.clear();
+            return Err(DbusError::IncorrectMessage(format!(
+                "message has {} fds, but only {} were received",
+                fd_count, received
+            )));
+        }
+        msg.fds = self.received_fds.drain(..fd_count).collect();
+        Ok(())
+    }
 
-            if received_byte_count < REPLY_BUF_SIZE {
-                // if received byte count is less than buffer size, then we got all
-                break;
+    /// function to send message of given type with given headers, body and fds
+    /// over the dbus connection, without waiting for any reply. The caller must specify
+    /// the destination, interface etc.etc. in the headers, this function will only take care
+    /// of sending the message. If any fds are given, the UnixFd header is added automatically.
+    /// Returns the serial of the sent message
+    pub fn send(
+        &mut self,
+        mtype: MessageType,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+    ) -> Result<u32> {
+        self.send_with_flags(mtype, MessageFlags::empty(), headers, body, fds)
+    }
+
+    /// Same as `send`, but with given flags set on the message. Use
+    /// `MessageFlags::NO_REPLY_EXPECTED` for method calls whose reply is not needed,
+    /// so the peer does not send one at all
+    pub fn send_with_flags(
+        &mut self,
+        mtype: MessageType,
+        flags: MessageFlags,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+    ) -> Result<u32> {
+        let mut message = Message::new(mtype, 0, headers, body)
+            .with_endian(self.endian)
+            .with_flags(flags);
+        message.fds = fds;
+        self.send_msg(message)
+    }
+
+    /// Send given message, such as one made with `Message::method_call` or other builders,
+    /// without waiting for any reply. The serial is assigned here, and the UnixFd header is set
+    /// from the fds of the message. The message is sent in its own endian,
+    /// as its body has already been serialized. Returns the serial of the sent message
+    pub fn send_msg(&mut self, message: Message) -> Result<u32> {
+        let serial = self.get_msg_id();
+        self.send_msg_with_serial(message, serial)?;
+        Ok(serial)
+    }
+
+    /// Same as `send_msg`, but with given serial, which must have been taken
+    /// from `get_msg_id` earlier. This lets the caller get ready for the reply
+    /// before the message is sent
+    pub(crate) fn send_msg_with_serial(&mut self, mut message: Message, serial: u32) -> Result<()> {
+        if !self.connected {
+            if self.reconnect.is_none() {
+                return Err(DbusError::Disconnected);
             }
+            self.reconnect()?;
+        }
+        if self.p2p {
+            // peers do not route messages, and the sender is set by the bus
+            message.headers.retain(|h| {
+                !matches!(
+                    h.kind,
+                    HeaderFieldKind::Destination | HeaderFieldKind::Sender
+                )
+            });
         }
-        Ok(ret)
+        // a received message being passed on would already have it
+        message
+            .headers
+            .retain(|h| h.kind != HeaderFieldKind::UnixFd);
+        let fds = std::mem::take(&mut message.fds);
+        if !fds.is_empty() {
+            if !self.unix_fd {
+                return Err(DbusError::ConnectionError(
+                    "fd passing is not enabled on this connection".into(),
+                ));
+            }
+            message.headers.push(Header {
+                kind: HeaderFieldKind::UnixFd,
+                value: HeaderFieldValue::U32(fds.len() as u32),
+            });
+        }
+
+        // the bus disconnects us on getting an invalid message, so do not send it at all
+        message.validate()?;
+        message.check_body()?;
+        message.serial = serial;
+        let bytes = message.serialize();
+        if bytes.len() > MAX_MESSAGE_LENGTH {
+            return Err(ValidationError::MessageTooLong(bytes.len()).into());
+        }
+        self.outgoing.push_back(OutgoingMessage {
+            bytes,
+            written: 0,
+            fds,
+        });
+        // if the socket is non-blocking, this might not write it all,
+        // and the rest is written by a later flush
+        self.flush()?;
+        Ok(())
+    }
+
+    /// Writes as much of the queued outgoing messages as the socket accepts.
+    /// Returns true if everything has been written. This is only needed in non-blocking mode,
+    /// where `send` queues whatever could not be written right away : when this returns false,
+    /// call it again once the socket is writable
+    pub fn flush(&mut self) -> Result<bool> {
+        let socket = self.socket.as_raw_fd();
+        while let Some(out) = self.outgoing.front_mut() {
+            let raw_fds: Vec<RawFd> = out.fds.iter().map(|fd| fd.as_raw_fd()).collect();
+            let cmsgs = if raw_fds.is_empty() {
+                vec![]
+            } else {
+                vec![socket::ControlMessage::ScmRights(&raw_fds)]
+            };
+
+            let res = socket::sendmsg::<()>(
+                socket,
+                &[IoSlice::new(&out.bytes[out.written..])],
+                &cmsgs,
+                // we want an error instead of getting killed if the peer has hung up
+                socket::MsgFlags::MSG_NOSIGNAL,
+                None,
+            );
+            match res {
+                Ok(n) => {
+                    out.written += n;
+                    // the receiver has its own copies of the fds now, so we can close ours
+                    out.fds.clear();
+                    if out.written == out.bytes.len() {
+                        self.outgoing.pop_front();
+                    }
+                }
+                Err(nix::errno::Errno::EAGAIN) => return Ok(false),
+                Err(nix::errno::Errno::EINTR) => {}
+                Err(nix::errno::Errno::EPIPE | nix::errno::Errno::ECONNRESET) => {
+                    return Err(self.disconnect())
+                }
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(true)
+    }
+
+    /// Check if there are queued outgoing messages, which need a `flush` once
+    /// the socket is writable
+    pub fn has_outgoing(&self) -> bool {
+        !self.outgoing.is_empty()
+    }
+
+    /// Check if the socket is in non-blocking mode
+    pub(crate) fn is_nonblocking(&self) -> Result<bool> {
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        let flags = OFlag::from_bits_truncate(fcntl(self.socket.as_raw_fd(), FcntlArg::F_GETFL)?);
+        Ok(flags.contains(OFlag::O_NONBLOCK))
+    }
+
+    /// Set the socket to non-blocking mode or back, for use in an external event loop.
+    /// Reads never block regardless of this, as they are always preceded by waiting for
+    /// the socket, so this only affects the writes, see `flush`
+    pub fn set_nonblocking(&self, nonblocking: bool) -> Result<()> {
+        use nix::fcntl::{fcntl, FcntlArg, OFlag};
+        let fd = self.socket.as_raw_fd();
+        let mut flags = OFlag::from_bits_truncate(fcntl(fd, FcntlArg::F_GETFL)?);
+        flags.set(OFlag::O_NONBLOCK, nonblocking);
+        fcntl(fd, FcntlArg::F_SETFL(flags))?;
+        Ok(())
     }
 
-    /// function to send message of given type with given headers and body
-    /// over the dbus connection. The caller must specify the destination, interface etc.etc.
-    /// in the headers, this function will only take care of sending the message and
-    /// returning the received messages. Note that the caller must check if any error
-    /// message was returned or not, this will not check that
+    /// function to send message of given type with given headers, body and fds
+    /// and wait for the reply to it, see `send` for details. This is meant for method calls,
+    /// as other messages do not get a reply. Any other messages received while waiting,
+    /// such as signals, are queued and can be taken with `pop_message` later.
+    /// This waits for the default timeout of the connection, see `send_message_with_timeout`.
+    /// Note that the caller must check if the reply is an error
+    /// message or not, this will not check that
     pub fn send_message(
         &mut self,
         mtype: MessageType,
         headers: Vec<Header>,
         body: Vec<u8>,
-    ) -> Result<Vec<Message>> {
-        let message = Message::new(mtype, self.get_msg_id(), headers, body);
-        let serialized = message.serialize();
+        fds: Vec<OwnedFd>,
+    ) -> Result<Message> {
+        self.send_message_with_timeout(mtype, headers, body, fds, self.timeout)
+    }
 
-        socket::sendmsg::<()>(
-            self.socket,
-            &[IoSlice::new(&serialized)],
-            &[],
-            socket::MsgFlags::empty(),
-            None,
-        )?;
+    /// Same as `send_message`, but waits for the reply only for the given time instead of the
+    /// default timeout of the connection. If no reply arrives in time, `DbusError::Timeout`
+    /// is returned and the reply is discarded if it arrives later
+    pub fn send_message_with_timeout(
+        &mut self,
+        mtype: MessageType,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+        timeout: Duration,
+    ) -> Result<Message> {
+        let mut message = Message::new(mtype, 0, headers, body).with_endian(self.endian);
+        message.fds = fds;
+        self.call_with_timeout(message, timeout)
+    }
 
-        let reply = self.receive_complete_response()?;
+    /// Send given method call, such as one made with `Message::method_call`, and wait for
+    /// its reply for the default timeout of the connection
+    pub fn call(&mut self, message: Message) -> Result<Message> {
+        self.call_with_timeout(message, self.timeout)
+    }
+
+    /// Same as `call`, but waits for the reply only for given time,
+    /// see `send_message_with_timeout`. Messages with `NO_REPLY_EXPECTED`
+    /// are not sent, as their reply would never arrive
+    pub fn call_with_timeout(&mut self, message: Message, timeout: Duration) -> Result<Message> {
+        message.check_reply_expected()?;
+        // a timeout too large to represent is as good as no timeout at all
+        let deadline = Instant::now().checked_add(timeout);
+        let serial = self.send_msg(message)?;
+        // on a non-blocking socket the message might not have been written completely
+        while !self.flush()? {
+            if !self.wait_ready(deadline, PollFlags::POLLOUT)? {
+                return Err(DbusError::Timeout(format!(
+                    "could not send message {}",
+                    serial
+                )));
+            }
+        }
+        self.wait_for_reply(serial, deadline)
+    }
+
+    /// Reads messages until we get the reply to message with given serial,
+    /// queueing all others
+    fn wait_for_reply(&mut self, serial: u32, deadline: Option<Instant>) -> Result<Message> {
+        // the reply may have been queued while we waited for some other reply
+        if let Some(pos) = self
+            .incoming
+            .iter()
+            .position(|m| m.reply_serial() == Some(serial))
+        {
+            // we can unwrap as we just found the position
+            return Ok(self.incoming.remove(pos).unwrap());
+        }
 
-        // note that a single received response can contain multiple
-        // messages, so we must deserialize it piece by piece
-        let mut ret = Vec::new();
-        let mut buf = &reply[..];
+        loop {
+            let msg = match self.read_message(deadline) {
+                Ok(Some(msg)) => msg,
+                Ok(None) => {
+                    self.abandoned.insert(serial);
+                    return Err(DbusError::Timeout(format!(
+                        "no reply received for message {}",
+                        serial
+                    )));
+                }
+                // a single malformed message has already been taken out of the buffer, so it
+                // is skipped as it need not have anything to do with our call. Messages which
+                // cannot be framed close the connection instead, and fail the call
+                Err(DbusError::IncorrectMessage(_) | DbusError::ValidationError(_))
+                    if self.connected =>
+                {
+                    continue
+                }
+                Err(e) => return Err(e),
+            };
+            match msg.reply_serial() {
+                Some(s) if s == serial => return Ok(msg),
+                // nobody is waiting for this one anymore
+                Some(s) if self.abandoned.remove(&s) => {}
+                _ => self.incoming.push_back(msg),
+            }
+        }
+    }
+
+    /// Reads whatever is available on the socket into the receive buffer, without blocking,
+    /// for use in an external event loop once the socket is readable. Use `dispatch_pending`
+    /// afterwards to get the received messages. Returns the number of bytes read.
+    /// If the peer has hung up, this returns `DbusError::Disconnected` with the Disconnected
+    /// signal queued. The connection is made again by `reconnect`, or by the next send if
+    /// a reconnect policy is set, and the event loop must then wait on the new `as_raw_fd()`
+    pub fn read_available(&mut self) -> Result<usize> {
+        if !self.connected {
+            return Err(DbusError::Disconnected);
+        }
+        let mut total = 0;
+        loop {
+            match self.read_more()? {
+                Some(0) => {
+                    // the messages received before hang up must be queued before the signal
+                    self.dispatch_pending()?;
+                    return Err(self.disconnect());
+                }
+                Some(n) => total += n,
+                None => return Ok(total),
+            }
+        }
+    }
 
-        while !buf.is_empty() {
-            let mut ctr = 0;
-            let msg = Message::deserialize(&buf[ctr..], &mut ctr)?;
-            // we reset the buf, because I couldn't figure out how the adjust_counter function
-            // should should be fixed to work correctly, and this solved that issue
-            buf = &buf[ctr..];
-            ret.push(msg);
+    /// Parses all the completely received messages in the receive buffer and queues them,
+    /// without blocking. Returns the number of messages queued, which can then be taken
+    /// with `pop_message`. Late replies to calls which have timed out are discarded
+    pub fn dispatch_pending(&mut self) -> Result<usize> {
+        let mut count = 0;
+        while let Some(msg) = self.take_buffered_message()? {
+            match msg.reply_serial() {
+                Some(s) if self.abandoned.remove(&s) => {}
+                _ => {
+                    self.incoming.push_back(msg);
+                    count += 1;
+                }
+            }
         }
-        Ok(ret)
+        Ok(count)
+    }
+
+    /// Take the oldest of the queued messages which were received while waiting
+    /// for a reply, but were not the reply itself, such as signals
+    pub fn pop_message(&mut self) -> Option<Message> {
+        self.incoming.pop_front()
+    }
+
+    /// Take all of the queued messages, oldest first
+    pub fn drain_incoming(&mut self) -> Vec<Message> {
+        self.incoming.drain(..).collect()
     }
 
     /// function to manage the message counter
-    fn get_msg_id(&mut self) -> u32 {
+    pub(crate) fn get_msg_id(&mut self) -> u32 {
         self.msg_ctr += 1;
         self.msg_ctr
     }
 
-    /// Create a proxy for given destination and path
-    pub fn proxy(&mut self, destination: String, path: String) -> Proxy {
+    /// Create a proxy for given destination and path, see `Proxy::new`
+    pub fn proxy(
+        &mut self,
+        destination: impl TryInto<BusName, Error = DbusError>,
+        path: impl TryInto<ObjectPath, Error = DbusError>,
+    ) -> Result<Proxy<'_>> {
         Proxy::new(self, destination, path)
     }
 }
+
+impl AsRawFd for DbusConnection {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl AsFd for DbusConnection {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.socket.as_fd()
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+    use crate::auth::{hex_decode, hex_encode, sha1};
+    use crate::names::InterfaceName;
+    use crate::serialize::DbusSerialize;
+    use std::net::TcpListener;
+
+    /// Reads one line of the auth protocol on the server side. This reads one byte
+    /// at a time, so that we do not consume any message bytes following the line
+    pub(crate) fn read_line(fd: RawFd) -> String {
+        let mut line = vec![];
+        while !line.ends_with(b"\r\n") {
+            let mut byte = [0];
+            assert_eq!(
+                socket::recv(fd, &mut byte, socket::MsgFlags::empty()).unwrap(),
+                1
+            );
+            line.push(byte[0]);
+        }
+        line.truncate(line.len() - 2);
+        String::from_utf8(line).unwrap()
+    }
+
+    pub(crate) fn write_line(fd: RawFd, line: &str) {
+        let line = format!("{}\r\n", line);
+        socket::send(fd, line.as_bytes(), socket::MsgFlags::empty()).unwrap();
+    }
+
+    /// Receives a single message on the server side, along with the fds sent with it
+    pub(crate) fn recv_message(fd: RawFd) -> Message {
+        // peek at the fixed header first, so we do not read any of the message after this one
+        let mut fixed = [0; FIXED_HEADER_LENGTH];
+        socket::recv(
+            fd,
+            &mut fixed,
+            socket::MsgFlags::MSG_PEEK | socket::MsgFlags::MSG_WAITALL,
+        )
+        .unwrap();
+        let mut buf = vec![0; Message::total_length(&fixed).unwrap()];
+        let mut cmsg_buf = nix::cmsg_space!([RawFd; 4]);
+        let received = socket::recvmsg::<()>(
+            fd,
+            &mut [IoSliceMut::new(&mut buf)],
+            Some(&mut cmsg_buf),
+            socket::MsgFlags::MSG_WAITALL,
+        )
+        .unwrap();
+        let mut fds = vec![];
+        for cmsg in received.cmsgs() {
+            if let socket::ControlMessageOwned::ScmRights(received_fds) = cmsg {
+                fds.extend(
+                    received_fds
+                        .into_iter()
+                        .map(|fd| unsafe { OwnedFd::from_raw_fd(fd) }),
+                );
+            }
+        }
+        let bytes = received.bytes;
+
+        let mut ctr = 0;
+        let mut msg = Message::deserialize(&buf[..bytes], &mut ctr).unwrap();
+        msg.fds = fds;
+        msg
+    }
+
+    /// Sends a method return for given call on the server side
+    pub(crate) fn send_reply(
+        fd: RawFd,
+        call: &Message,
+        signature: &str,
+        body: Vec<u8>,
+        fds: &[RawFd],
+    ) {
+        let mut headers = vec![Header {
+            kind: HeaderFieldKind::ReplySerial,
+            value: HeaderFieldValue::U32(call.serial),
+        }];
+        if !signature.is_empty() {
+            headers.push(Header {
+                kind: HeaderFieldKind::BodySignature,
+                value: HeaderFieldValue::String(signature.into()),
+            });
+        }
+        if !fds.is_empty() {
+            headers.push(Header {
+                kind: HeaderFieldKind::UnixFd,
+                value: HeaderFieldValue::U32(fds.len() as u32),
+            });
+        }
+        let reply = Message::new(MessageType::MethodReturn, call.serial + 1000, headers, body);
+        let serialized = reply.serialize();
+        let cmsgs = if fds.is_empty() {
+            vec![]
+        } else {
+            vec![socket::ControlMessage::ScmRights(fds)]
+        };
+        socket::sendmsg::<()>(
+            fd,
+            &[IoSlice::new(&serialized)],
+            &cmsgs,
+            socket::MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+    }
+
+    /// Replies to the hello call sent after authentication, giving the client a unique name
+    pub(crate) fn reply_hello(fd: RawFd) {
+        let hello = recv_message(fd);
+        let mut body = vec![];
+        ":1.1".to_string().serialize(&mut body, &mut vec![]);
+        send_reply(fd, &hello, "s", body, &[]);
+    }
+
+    /// Does the server side of external auth and hello, agreeing to pass fds if asked
+    pub(crate) fn accept_external(fd: RawFd) {
+        let mut nul = [1];
+        socket::recv(fd, &mut nul, socket::MsgFlags::empty()).unwrap();
+        assert_eq!(nul, [0]);
+        assert!(read_line(fd).starts_with("AUTH EXTERNAL"));
+        write_line(fd, "OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2");
+        let mut line = read_line(fd);
+        if line == "NEGOTIATE_UNIX_FD" {
+            write_line(fd, "AGREE_UNIX_FD");
+            line = read_line(fd);
+        }
+        assert_eq!(line, "BEGIN");
+        reply_hello(fd);
+    }
+
+    /// Accepts a client on the listener, and replies to its first two calls in reverse
+    /// order with 1 and 2 respectively, sending a signal in between. This is the peer
+    /// for testing calls made concurrently. Returns the socket, for the caller to close
+    pub(crate) fn serve_reversed_replies(listener: OwnedFd) -> RawFd {
+        let fd = socket::accept(listener.as_raw_fd()).unwrap();
+        accept_external(fd);
+
+        let first = recv_message(fd);
+        let second = recv_message(fd);
+        let mut body = vec![];
+        2_u32.serialize(&mut body, &mut vec![]);
+        send_reply(fd, &second, "u", body, &[]);
+        let mut signal =
+            Message::signal("/org/example/Test", "org.example.Test", "Changed").unwrap();
+        signal.serial = 1;
+        socket::send(fd, &signal.serialize(), socket::MsgFlags::empty()).unwrap();
+        let mut body = vec![];
+        1_u32.serialize(&mut body, &mut vec![]);
+        send_reply(fd, &first, "u", body, &[]);
+        fd
+    }
+
+    /// Creates a listening socket in abstract namespace with given name
+    pub(crate) fn listen_abstract(name: &str) -> OwnedFd {
+        let listener = socket::socket(
+            socket::AddressFamily::Unix,
+            socket::SockType::Stream,
+            socket::SockFlag::empty(),
+            None,
+        )
+        .unwrap();
+        socket::bind(
+            listener,
+            &socket::UnixAddr::new_abstract(name.as_bytes()).unwrap(),
+        )
+        .unwrap();
+        socket::listen(listener, 1).unwrap();
+        unsafe { OwnedFd::from_raw_fd(listener) }
+    }
+
+    #[test]
+    fn test_connect_abstract() {
+        let name = format!("dbus-native-test-{}", std::process::id());
+        let _listener = listen_abstract(&name);
+
+        let conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        assert_eq!(
+            conn.address().unwrap().transport,
+            Transport::UnixAbstract(name.clone().into_bytes())
+        );
+
+        // tmpdir can only be listened on, so it must be skipped in favour of next address
+        let conn =
+            DbusConnection::new(&format!("unix:tmpdir=/tmp;unix:abstract={}", name)).unwrap();
+        assert!(matches!(
+            conn.address().unwrap().transport,
+            Transport::UnixAbstract(_)
+        ));
+    }
+
+    #[test]
+    fn test_message_framing() {
+        let name = format!("dbus-native-test-framing-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            let mut nul = [1];
+            socket::recv(fd, &mut nul, socket::MsgFlags::empty()).unwrap();
+            read_line(fd);
+            // server can send the auth reply in pieces
+            socket::send(fd, b"OK 1d8a1b23e6e8", socket::MsgFlags::empty()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            socket::send(fd, b"c5dbb0b1b0d565a1f3f2\r\n", socket::MsgFlags::empty()).unwrap();
+            assert_eq!(read_line(fd), "NEGOTIATE_UNIX_FD");
+            write_line(fd, "AGREE_UNIX_FD");
+            assert_eq!(read_line(fd), "BEGIN");
+
+            // send the hello reply split at an awkward place, with a delay in between
+            let hello = recv_message(fd);
+            let mut body = vec![];
+            ":1.1".to_string().serialize(&mut body, &mut vec![]);
+            let reply = Message::new(
+                MessageType::MethodReturn,
+                1,
+                vec![
+                    Header {
+                        kind: HeaderFieldKind::ReplySerial,
+                        value: HeaderFieldValue::U32(hello.serial),
+                    },
+                    Header {
+                        kind: HeaderFieldKind::BodySignature,
+                        value: HeaderFieldValue::String("s".into()),
+                    },
+                ],
+                body,
+            )
+            .serialize();
+            socket::send(fd, &reply[..10], socket::MsgFlags::empty()).unwrap();
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            socket::send(fd, &reply[10..], socket::MsgFlags::empty()).unwrap();
+
+            // reply with a body larger than a single read, followed by another message
+            let call = recv_message(fd);
+            let mut body = vec![];
+            vec![7_u32; REPLY_BUF_SIZE].serialize(&mut body, &mut vec![]);
+            send_reply(fd, &call, "au", body, &[]);
+            send_reply(fd, &call, "", vec![], &[]);
+            let _ = nix::unistd::close(fd);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        assert_eq!(conn.unique_name(), Some(":1.1"));
+
+        let mut proxy = conn.proxy("org.example.Test", "/org/example/Test").unwrap();
+        let reply: Vec<u32> = proxy
+            .method_call::<(), _>("org.example.Test", "Get", None)
+            .unwrap();
+        assert_eq!(reply, vec![7; REPLY_BUF_SIZE]);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_fd_passing() {
+        let name = format!("dbus-native-test-fd-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            accept_external(fd);
+
+            // echo back the fd we got
+            let call = recv_message(fd);
+            assert_eq!(call.fds.len(), 1);
+            let mut body = vec![];
+            0_u32.serialize(&mut body, &mut vec![]);
+            send_reply(fd, &call, "h", body, &[call.fds[0].as_raw_fd()]);
+            let _ = nix::unistd::close(fd);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        assert!(conn.unix_fd_enabled());
+        assert_eq!(conn.unique_name(), Some(":1.1"));
+
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let read_end = unsafe { OwnedFd::from_raw_fd(read_end) };
+        let mut proxy = conn.proxy("org.example.Echo", "/org/example/Echo").unwrap();
+        let echoed: OwnedFd = proxy
+            .method_call("org.example.Echo", "Echo", Some(read_end))
+            .unwrap();
+        server.join().unwrap();
+
+        // the fd we got back must be the read end of the same pipe
+        nix::unistd::write(write_end, b"ping").unwrap();
+        let mut buf = [0; 4];
+        nix::unistd::read(echoed.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        let _ = nix::unistd::close(write_end);
+    }
+
+    #[test]
+    fn test_reply_matching() {
+        let name = format!("dbus-native-test-matching-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            accept_external(fd);
+
+            let call = recv_message(fd);
+            // a signal and a reply to some other call arrive before the actual reply
+            let signal = Message::new(
+                MessageType::Signal,
+                1,
+                vec![
+                    Header {
+                        kind: HeaderFieldKind::Path,
+                        value: HeaderFieldValue::String("/org/example/Test".into()),
+                    },
+                    Header {
+                        kind: HeaderFieldKind::Interface,
+                        value: HeaderFieldValue::String("org.example.Test".into()),
+                    },
+                    Header {
+                        kind: HeaderFieldKind::Member,
+                        value: HeaderFieldValue::String("Changed".into()),
+                    },
+                ],
+                vec![],
+            )
+            .serialize();
+            socket::send(fd, &signal, socket::MsgFlags::empty()).unwrap();
+            let other = Message::new(MessageType::MethodReturn, call.serial + 1, vec![], vec![]);
+            send_reply(fd, &other, "", vec![], &[]);
+
+            let mut body = vec![];
+            42_u32.serialize(&mut body, &mut vec![]);
+            send_reply(fd, &call, "u", body, &[]);
+            let _ = nix::unistd::close(fd);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+
+        let mut proxy = conn.proxy("org.example.Test", "/org/example/Test").unwrap();
+        let reply: u32 = proxy
+            .method_call::<(), _>("org.example.Test", "Get", None)
+            .unwrap();
+        assert_eq!(reply, 42);
+        server.join().unwrap();
+
+        let signal = conn.pop_message().unwrap();
+        assert_eq!(signal.preamble.mtype, MessageType::Signal);
+        let queued = conn.drain_incoming();
+        assert_eq!(queued.len(), 1);
+        assert_eq!(queued[0].preamble.mtype, MessageType::MethodReturn);
+        assert!(conn.pop_message().is_none());
+    }
+
+    #[test]
+    fn test_call_timeout() {
+        let name = format!("dbus-native-test-timeout-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            accept_external(fd);
+
+            // do not reply to the first call until the second one is made
+            let slow = recv_message(fd);
+            let call = recv_message(fd);
+            let mut body = vec![];
+            1_u32.serialize(&mut body, &mut vec![]);
+            send_reply(fd, &slow, "u", body, &[]);
+            let mut body = vec![];
+            2_u32.serialize(&mut body, &mut vec![]);
+            send_reply(fd, &call, "u", body, &[]);
+            let _ = nix::unistd::close(fd);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        assert_eq!(conn.timeout(), DEFAULT_TIMEOUT);
+
+        let mut proxy = conn.proxy("org.example.Test", "/org/example/Test").unwrap();
+        let res = proxy.method_call_with_timeout::<(), u32>(
+            "org.example.Test",
+            "Slow",
+            None,
+            Duration::from_millis(50),
+        );
+        assert!(matches!(res, Err(DbusError::Timeout(_))));
+
+        // the late reply to the first call must not be taken as the reply to this one
+        let reply: u32 = proxy
+            .method_call::<(), _>("org.example.Test", "Get", None)
+            .unwrap();
+        assert_eq!(reply, 2);
+        server.join().unwrap();
+        assert!(conn.pop_message().is_none());
+    }
+
+    #[test]
+    fn test_disconnect() {
+        let name = format!("dbus-native-test-disconnect-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            accept_external(fd);
+            // hang up without replying
+            recv_message(fd);
+            nix::unistd::close(fd).unwrap();
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        assert!(conn.is_connected());
+
+        let mut proxy = conn.proxy("org.example.Test", "/org/example/Test").unwrap();
+        let res = proxy.method_call::<(), u32>("org.example.Test", "Get", None);
+        assert!(matches!(res, Err(DbusError::Disconnected)));
+        server.join().unwrap();
+
+        // further calls fail right away, without the signal being queued again
+        let res = proxy.method_call::<(), u32>("org.example.Test", "Get", None);
+        assert!(matches!(res, Err(DbusError::Disconnected)));
+        assert!(!conn.is_connected());
+
+        let signal = conn.pop_message().unwrap();
+        assert_eq!(signal.preamble.mtype, MessageType::Signal);
+        assert!(matches!(
+            signal.get_header(&HeaderFieldKind::Interface),
+            Some(HeaderFieldValue::String(s)) if s == LOCAL_INTERFACE
+        ));
+        assert!(matches!(
+            signal.get_header(&HeaderFieldKind::Member),
+            Some(HeaderFieldValue::String(s)) if s == "Disconnected"
+        ));
+        assert!(conn.pop_message().is_none());
+        conn.close().unwrap();
+    }
+
+    #[test]
+    fn test_event_loop() {
+        let name = format!("dbus-native-test-event-loop-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            accept_external(fd);
+
+            let call = recv_message(fd);
+            send_reply(fd, &call, "", vec![], &[]);
+            let _ = nix::unistd::close(fd);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        conn.set_nonblocking(true).unwrap();
+
+        // nothing has arrived yet, so this must not block
+        assert_eq!(conn.read_available().unwrap(), 0);
+        assert_eq!(conn.dispatch_pending().unwrap(), 0);
+
+        let serial = conn
+            .send(
+                MessageType::MethodCall,
+                vec![
+                    Header {
+                        kind: HeaderFieldKind::Path,
+                        value: HeaderFieldValue::String("/org/example/Test".into()),
+                    },
+                    Header {
+                        kind: HeaderFieldKind::Member,
+                        value: HeaderFieldValue::String("Get".into()),
+                    },
+                ],
+                vec![],
+                vec![],
+            )
+            .unwrap();
+        assert!(conn.flush().unwrap());
+        assert!(!conn.has_outgoing());
+
+        // wait for the reply and the hang up like an external event loop would
+        let mut queued = vec![];
+        while conn.is_connected() {
+            let mut fds = [PollFd::new(conn.as_raw_fd(), PollFlags::POLLIN)];
+            poll(&mut fds, -1).unwrap();
+            let res = conn.read_available();
+            conn.dispatch_pending().unwrap();
+            queued.extend(conn.drain_incoming());
+            if res.is_err() {
+                assert!(matches!(res, Err(DbusError::Disconnected)));
+            }
+        }
+        server.join().unwrap();
+
+        assert_eq!(queued.len(), 2);
+        assert_eq!(queued[0].reply_serial(), Some(serial));
+        assert_eq!(queued[1].preamble.mtype, MessageType::Signal);
+    }
+
+    #[test]
+    fn test_peer_to_peer() {
+        let name = format!("dbus-native-test-p2p-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            let mut nul = [1];
+            socket::recv(fd, &mut nul, socket::MsgFlags::empty()).unwrap();
+            assert!(read_line(fd).starts_with("AUTH EXTERNAL"));
+            write_line(fd, "OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2");
+            assert_eq!(read_line(fd), "NEGOTIATE_UNIX_FD");
+            write_line(fd, "AGREE_UNIX_FD");
+            assert_eq!(read_line(fd), "BEGIN");
+
+            // the first message must be our call and not the hello
+            let call = recv_message(fd);
+            assert!(matches!(
+                call.get_header(&HeaderFieldKind::Member),
+                Some(HeaderFieldValue::String(m)) if m == "Reload"
+            ));
+            assert!(call.get_header(&HeaderFieldKind::Destination).is_none());
+            send_reply(fd, &call, "", vec![], &[]);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.set_peer_to_peer(true);
+        conn.authenticate(None).unwrap();
+        assert!(conn.is_peer_to_peer());
+        assert_eq!(conn.unique_name(), None);
+
+        let mut proxy = conn
+            .proxy("org.freedesktop.systemd1", "/org/freedesktop/systemd1")
+            .unwrap();
+        proxy
+            .method_call::<(), ()>("org.freedesktop.systemd1.Manager", "Reload", None)
+            .unwrap();
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_pair() {
+        let (mut client, mut server) = DbusConnection::pair().unwrap();
+        assert!(client.address().is_none());
+
+        let server = std::thread::spawn(move || {
+            let call = server.read_message(None).unwrap().unwrap();
+            assert_eq!(call.fds.len(), 1);
+            let mut body = vec![];
+            0_u32.serialize(&mut body, &mut vec![]);
+            server
+                .send(
+                    MessageType::MethodReturn,
+                    vec![
+                        Header {
+                            kind: HeaderFieldKind::ReplySerial,
+                            value: HeaderFieldValue::U32(call.serial),
+                        },
+                        Header {
+                            kind: HeaderFieldKind::BodySignature,
+                            value: HeaderFieldValue::String("h".into()),
+                        },
+                    ],
+                    body,
+                    call.fds,
+                )
+                .unwrap();
+        });
+
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let read_end = unsafe { OwnedFd::from_raw_fd(read_end) };
+        let mut proxy = client
+            .proxy("org.example.Echo", "/org/example/Echo")
+            .unwrap();
+        let echoed: OwnedFd = proxy
+            .method_call("org.example.Echo", "Echo", Some(read_end))
+            .unwrap();
+        server.join().unwrap();
+
+        nix::unistd::write(write_end, b"ping").unwrap();
+        let mut buf = [0; 4];
+        nix::unistd::read(echoed.as_raw_fd(), &mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+        let _ = nix::unistd::close(write_end);
+    }
+
+    #[test]
+    fn test_reconnect() {
+        let name = format!("dbus-native-test-reconnect-{}", std::process::id());
+        let listener = listen_abstract(&name);
+        let rule = "type='signal',interface='org.example.Test'";
+
+        let server = std::thread::spawn(move || {
+            // a bus which restarts after registering the match rule and the name
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            accept_external(fd);
+            for _ in 0..2 {
+                let call = recv_message(fd);
+                let mut body = vec![];
+                1_u32.serialize(&mut body, &mut vec![]);
+                send_reply(fd, &call, "u", body, &[]);
+            }
+            nix::unistd::close(fd).unwrap();
+
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            let mut nul = [1];
+            socket::recv(fd, &mut nul, socket::MsgFlags::empty()).unwrap();
+            assert!(read_line(fd).starts_with("AUTH EXTERNAL"));
+            write_line(fd, "OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2");
+            assert_eq!(read_line(fd), "NEGOTIATE_UNIX_FD");
+            write_line(fd, "AGREE_UNIX_FD");
+            assert_eq!(read_line(fd), "BEGIN");
+            let hello = recv_message(fd);
+            let mut body = vec![];
+            ":1.2".to_string().serialize(&mut body, &mut vec![]);
+            send_reply(fd, &hello, "s", body, &[]);
+
+            // match rule and name must be registered again, before anything else,
+            // but the name has been taken by someone else in the meantime
+            let mut members = vec![];
+            for _ in 0..3 {
+                let call = recv_message(fd);
+                match call.get_header(&HeaderFieldKind::Member) {
+                    Some(HeaderFieldValue::String(m)) => members.push(m.clone()),
+                    _ => panic!("call without member"),
+                }
+                if members.last().unwrap() == "RequestName" {
+                    let mut error = Message::error(
+                        &call,
+                        "org.freedesktop.DBus.Error.AccessDenied",
+                        "name is taken",
+                    )
+                    .unwrap();
+                    error.serial = 1000;
+                    socket::send(fd, &error.serialize(), socket::MsgFlags::empty()).unwrap();
+                    continue;
+                }
+                let mut body = vec![];
+                1_u32.serialize(&mut body, &mut vec![]);
+                send_reply(fd, &call, "u", body, &[]);
+            }
+            assert_eq!(members, vec!["AddMatch", "RequestName", "Get"]);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        conn.set_reconnect_policy(Some(ReconnectPolicy {
+            max_attempts: Some(5),
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        }));
+        conn.add_match(rule).unwrap();
+        assert_eq!(conn.request_name("org.example.Test", 4).unwrap(), 1);
+
+        // the call in flight when the bus goes away fails, and next one reconnects
+        let mut proxy = conn.proxy("org.example.Test", "/org/example/Test").unwrap();
+        let res = proxy.method_call::<(), u32>("org.example.Test", "Get", None);
+        assert!(matches!(res, Err(DbusError::Disconnected)));
+        let res: u32 = proxy
+            .method_call::<(), _>("org.example.Test", "Get", None)
+            .unwrap();
+        assert_eq!(res, 1);
+        server.join().unwrap();
+        assert_eq!(conn.unique_name(), Some(":1.2"));
+
+        let disconnected = conn.pop_message().unwrap();
+        assert!(matches!(
+            disconnected.get_header(&HeaderFieldKind::Member),
+            Some(HeaderFieldValue::String(m)) if m == "Disconnected"
+        ));
+        let reconnected = conn.pop_message().unwrap();
+        assert!(matches!(
+            reconnected.get_header(&HeaderFieldKind::Member),
+            Some(HeaderFieldValue::String(m)) if m == "Reconnected"
+        ));
+        let names = <(String, String)>::deserialize(&reconnected.body, &mut 0, &mut []).unwrap();
+        assert_eq!(names, (":1.1".to_string(), ":1.2".to_string()));
+        let failed = conn.pop_message().unwrap();
+        assert!(matches!(
+            failed.get_header(&HeaderFieldKind::Member),
+            Some(HeaderFieldValue::String(m)) if m == "RequestNameFailed"
+        ));
+        let failed = <(String, String)>::deserialize(&failed.body, &mut 0, &mut []).unwrap();
+        assert_eq!(
+            failed,
+            ("org.example.Test".to_string(), "name is taken".to_string())
+        );
+    }
+
+    #[test]
+    fn test_reconnect_after_read() {
+        let name = format!("dbus-native-test-reconnect-read-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            // a bus which restarts right after the client has connected
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            accept_external(fd);
+            nix::unistd::close(fd).unwrap();
+
+            let fd = socket::accept(listener.as_raw_fd()).unwrap();
+            accept_external(fd);
+            let mut signal =
+                Message::signal("/org/example/Test", "org.example.Test", "Changed").unwrap();
+            signal.serial = 1;
+            socket::send(fd, &signal.serialize(), socket::MsgFlags::empty()).unwrap();
+            fd
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        conn.set_reconnect_policy(Some(ReconnectPolicy {
+            max_attempts: Some(5),
+            initial_delay: Duration::from_millis(10),
+            max_delay: Duration::from_millis(50),
+        }));
+        conn.set_nonblocking(true).unwrap();
+
+        // nothing is ever sent, so only reading can find out that the bus went away,
+        // and the event loop reconnects itself then
+        let mut received = vec![];
+        while received.len() < 3 {
+            let mut fds = [PollFd::new(conn.as_raw_fd(), PollFlags::POLLIN)];
+            poll(&mut fds, -1).unwrap();
+            match conn.read_available() {
+                Ok(_) => {}
+                Err(DbusError::Disconnected) => {
+                    assert!(!conn.is_connected());
+                    conn.reconnect().unwrap();
+                }
+                Err(e) => panic!("unexpected error {:?}", e),
+            }
+            conn.dispatch_pending().unwrap();
+            received.extend(conn.drain_incoming());
+        }
+        let fd = server.join().unwrap();
+
+        let members: Vec<_> = received
+            .iter()
+            .map(|m| match m.get_header(&HeaderFieldKind::Member) {
+                Some(HeaderFieldValue::String(m)) => m.as_str(),
+                _ => panic!("message without member"),
+            })
+            .collect();
+        assert_eq!(members, vec!["Disconnected", "Reconnected", "Changed"]);
+        assert!(conn.is_connected());
+        assert!(conn.is_nonblocking().unwrap());
+        let _ = nix::unistd::close(fd);
+    }
+
+    #[test]
+    fn test_endian() {
+        let (mut client, mut server) = DbusConnection::pair().unwrap();
+        client.set_endian(Endian::Big);
+
+        // the server replies in little endian to a big endian call
+        let server = std::thread::spawn(move || {
+            let call = server.read_message(None).unwrap().unwrap();
+            assert_eq!(call.preamble.endian(), Endian::Big);
+            let (text, count) = <(String, u32)>::deserialize_endian(
+                &call.body,
+                &mut 0,
+                &mut [],
+                call.preamble.endian(),
+            )
+            .unwrap();
+            let mut body = vec![];
+            text.repeat(count as usize)
+                .serialize(&mut body, &mut vec![]);
+            server
+                .send(
+                    MessageType::MethodReturn,
+                    vec![
+                        Header {
+                            kind: HeaderFieldKind::ReplySerial,
+                            value: HeaderFieldValue::U32(call.serial),
+                        },
+                        Header {
+                            kind: HeaderFieldKind::BodySignature,
+                            value: HeaderFieldValue::String("s".into()),
+                        },
+                    ],
+                    body,
+                    vec![],
+                )
+                .unwrap();
+        });
+
+        let mut proxy = client
+            .proxy("org.example.Repeat", "/org/example/Repeat")
+            .unwrap();
+        let repeated: String = proxy
+            .method_call(
+                "org.example.Repeat",
+                "Repeat",
+                Some(("ab".to_string(), 3_u32)),
+            )
+            .unwrap();
+        assert_eq!(repeated, "ababab");
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_no_reply() {
+        let (mut client, mut server) = DbusConnection::pair().unwrap();
+
+        // the server never replies, so this would block if we waited for a reply
+        let mut proxy = client.proxy("org.example.Log", "/org/example/Log").unwrap();
+        proxy
+            .method_call_no_reply("org.example.Log", "Log", Some("hello".to_string()))
+            .unwrap();
+
+        let call = server.read_message(None).unwrap().unwrap();
+        assert_eq!(call.preamble.mtype, MessageType::MethodCall);
+        assert!(call
+            .preamble
+            .flags()
+            .contains(MessageFlags::NO_REPLY_EXPECTED));
+
+        // waiting for the reply to such a call fails right away, without sending it
+        let call = Message::method_call(
+            "org.example.Log",
+            "/org/example/Log",
+            "org.example.Log",
+            "Log",
+        )
+        .unwrap()
+        .with_flags(MessageFlags::NO_REPLY_EXPECTED);
+        assert!(matches!(
+            client.call(call),
+            Err(DbusError::ValidationError(
+                ValidationError::ReplyNotExpected
+            ))
+        ));
+        assert!(server.try_read_message().unwrap().is_none());
+    }
+
+    #[test]
+    fn test_validation() {
+        let (mut client, mut server) = DbusConnection::pair().unwrap();
+
+        // invalid messages are not sent at all, so the peer does not see them
+        let headers = vec![Header {
+            kind: HeaderFieldKind::Member,
+            value: HeaderFieldValue::String("Get".into()),
+        }];
+        assert!(matches!(
+            client.send(MessageType::MethodCall, headers, vec![], vec![]),
+            Err(DbusError::ValidationError(
+                ValidationError::MissingHeader { .. }
+            ))
+        ));
+        // names are checked as soon as they are given
+        assert!(matches!(
+            client.proxy("org.example.Test", "org/example/Test"),
+            Err(DbusError::ValidationError(
+                ValidationError::InvalidObjectPath(_)
+            ))
+        ));
+        let mut proxy = client
+            .proxy("org.example.Test", "/org/example/Test")
+            .unwrap();
+        assert!(matches!(
+            proxy.method_call_no_reply::<()>("org.example.Test", "Get-1", None),
+            Err(DbusError::ValidationError(ValidationError::InvalidMember(
+                _
+            )))
+        ));
+        // the serializers cannot fail, so arrays which are too long are caught on sending
+        let call = Message::method_call(
+            "org.example.Test",
+            "/org/example/Test",
+            "org.example.Test",
+            "Set",
+        )
+        .unwrap()
+        .with_body(&vec![0_u64; MAX_ARRAY_LENGTH / 8 + 1])
+        .unwrap();
+        assert!(matches!(
+            client.send_msg(call),
+            Err(DbusError::ValidationError(ValidationError::ArrayTooLong(_)))
+        ));
+        let signature = Header {
+            kind: HeaderFieldKind::BodySignature,
+            value: HeaderFieldValue::String("au".into()),
+        };
+        let headers = vec![
+            Header {
+                kind: HeaderFieldKind::Path,
+                value: HeaderFieldValue::String("/org/example/Test".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Member,
+                value: HeaderFieldValue::String("Set".into()),
+            },
+            signature,
+        ];
+        let body = ((MAX_ARRAY_LENGTH + 4) as u32).to_le_bytes().to_vec();
+        assert!(matches!(
+            client.send(MessageType::MethodCall, headers.clone(), body, vec![]),
+            Err(DbusError::ValidationError(ValidationError::ArrayTooLong(_)))
+        ));
+        // as is a body which does not match its signature
+        assert!(matches!(
+            client.send(MessageType::MethodCall, headers, vec![0; 12], vec![]),
+            Err(DbusError::IncorrectMessage(_))
+        ));
+        assert!(server.try_read_message().unwrap().is_none());
+
+        // receiving a message which is too long closes the connection, as it cannot be skipped
+        let mut preamble = vec![b'l', 1, 0, 1];
+        preamble.extend_from_slice(&(MAX_MESSAGE_LENGTH as u32).to_le_bytes());
+        preamble.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+        socket::send(client.as_raw_fd(), &preamble, socket::MsgFlags::empty()).unwrap();
+        assert!(matches!(
+            server.read_message(None),
+            Err(DbusError::ValidationError(ValidationError::MessageTooLong(
+                _
+            )))
+        ));
+        assert!(!server.is_connected());
+    }
+
+    #[test]
+    fn test_skip_malformed() {
+        let (mut client, mut server) = DbusConnection::pair().unwrap();
+
+        let server = std::thread::spawn(move || {
+            let call = server.read_message(None).unwrap().unwrap();
+            // a reply without reply serial, and a message of an unknown type
+            let missing = Message::new(MessageType::MethodReturn, 7, vec![], vec![]).serialize();
+            let mut unknown = missing.clone();
+            unknown[1] = 9;
+            for bytes in [missing, unknown] {
+                socket::send(server.as_raw_fd(), &bytes, socket::MsgFlags::empty()).unwrap();
+            }
+            server
+                .send_msg(Message::method_return(&call).with_body(&1_u32).unwrap())
+                .unwrap();
+            server
+        });
+
+        // the malformed messages are not our reply, so they must not fail the call
+        let call = Message::method_call(
+            "org.example.Test",
+            "/org/example/Test",
+            "org.example.Test",
+            "Get",
+        )
+        .unwrap();
+        let reply = client.call(call).unwrap();
+        assert_eq!(u32::deserialize(&reply.body, &mut 0, &mut []).unwrap(), 1);
+        assert!(client.is_connected());
+        assert!(client.pop_message().is_none());
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_bus_address() {
+        fn env(vars: &[(&str, &str)]) -> impl Fn(&str) -> Option<OsString> {
+            let vars: Vec<_> = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), OsString::from(v)))
+                .collect();
+            move |var| vars.iter().find(|(k, _)| k == var).map(|(_, v)| v.clone())
+        }
+
+        // the env var is used as is, and takes precedence over the runtime dir
+        let session = env(&[
+            (
+                "DBUS_SESSION_BUS_ADDRESS",
+                "unix:path=/tmp/bus;tcp:host=localhost",
+            ),
+            ("XDG_RUNTIME_DIR", "/run/user/1000"),
+        ]);
+        assert_eq!(
+            session_bus_address(session).unwrap(),
+            "unix:path=/tmp/bus;tcp:host=localhost"
+        );
+        // an empty one is treated as unset, and the runtime dir is escaped
+        let session = env(&[
+            ("DBUS_SESSION_BUS_ADDRESS", ""),
+            ("XDG_RUNTIME_DIR", "/run/user/a b"),
+        ]);
+        assert_eq!(
+            session_bus_address(session).unwrap(),
+            "unix:path=/run/user/a%20b/bus"
+        );
+        assert!(matches!(
+            session_bus_address(env(&[("XDG_RUNTIME_DIR", "")])),
+            Err(DbusError::ConnectionError(_))
+        ));
+
+        let system = env(&[("DBUS_SYSTEM_BUS_ADDRESS", "unix:path=/tmp/system")]);
+        assert_eq!(system_bus_address(system), "unix:path=/tmp/system");
+        assert_eq!(
+            system_bus_address(env(&[("DBUS_SYSTEM_BUS_ADDRESS", "")])),
+            DEFAULT_SYSTEM_BUS_ADDRESS
+        );
+        assert_eq!(system_bus_address(env(&[])), DEFAULT_SYSTEM_BUS_ADDRESS);
+    }
+
+    #[test]
+    fn test_malformed_fds() {
+        use std::os::unix::fs::MetadataExt;
+        let inode = |fd: &OwnedFd| {
+            std::fs::File::from(fd.try_clone().unwrap())
+                .metadata()
+                .unwrap()
+                .ino()
+        };
+
+        let (mut client, mut server) = DbusConnection::pair().unwrap();
+        let (first, _first_write) = nix::unistd::pipe().unwrap();
+        let (second, _second_write) = nix::unistd::pipe().unwrap();
+        let second = unsafe { OwnedFd::from_raw_fd(second) };
+
+        // a reply without reply serial, which comes with an fd
+        let headers = vec![Header {
+            kind: HeaderFieldKind::UnixFd,
+            value: HeaderFieldValue::U32(1),
+        }];
+        let malformed = Message::new(MessageType::MethodReturn, 7, headers, vec![]).serialize();
+        socket::sendmsg::<()>(
+            server.as_raw_fd(),
+            &[IoSlice::new(&malformed)],
+            &[socket::ControlMessage::ScmRights(&[first])],
+            socket::MsgFlags::empty(),
+            None,
+        )
+        .unwrap();
+        let signal = Message::signal("/org/example/Test", "org.example.Test", "Changed")
+            .unwrap()
+            .with_body(&second)
+            .unwrap();
+        server.send_msg(signal).unwrap();
+
+        assert!(matches!(
+            client.read_message(None),
+            Err(DbusError::ValidationError(_))
+        ));
+        // the fd of the malformed message is not passed on to the next one
+        let signal = client.read_message(None).unwrap().unwrap();
+        assert_eq!(signal.fds.len(), 1);
+        assert_eq!(inode(&signal.fds[0]), inode(&second));
+        assert!(client.received_fds.is_empty());
+        let _ = nix::unistd::close(first);
+    }
+
+    #[test]
+    fn test_builders() {
+        let (mut client, mut server) = DbusConnection::pair().unwrap();
+
+        let server = std::thread::spawn(move || {
+            for _ in 0..2 {
+                let call = server.read_message(None).unwrap().unwrap();
+                let (a, b) = <(u32, u32)>::deserialize(&call.body, &mut 0, &mut []).unwrap();
+                let reply = match a.checked_add(b) {
+                    Some(sum) => Message::method_return(&call).with_body(&sum).unwrap(),
+                    None => {
+                        Message::error(&call, "org.example.Error.Overflow", "too large").unwrap()
+                    }
+                };
+                server.send_msg(reply).unwrap();
+            }
+        });
+
+        let call = Message::method_call(
+            "org.example.Calc",
+            "/org/example/Calc",
+            "org.example.Calc",
+            "Add",
+        )
+        .unwrap()
+        .with_body(&(1_u32, 2_u32))
+        .unwrap();
+        let reply = client.call(call).unwrap();
+        assert_eq!(reply.preamble.mtype, MessageType::MethodReturn);
+        assert_eq!(u32::deserialize(&reply.body, &mut 0, &mut []).unwrap(), 3);
+
+        let mut proxy = client
+            .proxy("org.example.Calc", "/org/example/Calc")
+            .unwrap();
+        // names which were already checked can be given as well
+        let interface = InterfaceName::new("org.example.Calc").unwrap();
+        let res = proxy.method_call::<_, u32>(&interface, "Add", Some((u32::MAX, 1_u32)));
+        assert!(matches!(
+            res,
+            Err(DbusError::MethodError { name, message })
+                if name == "org.example.Error.Overflow" && message == "too large"
+        ));
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_from_fd() {
+        let (a, b) = socket::socketpair(
+            socket::AddressFamily::Unix,
+            socket::SockType::Stream,
+            None,
+            socket::SockFlag::empty(),
+        )
+        .unwrap();
+        let (a, b) = unsafe { (OwnedFd::from_raw_fd(a), OwnedFd::from_raw_fd(b)) };
+
+        let server = std::thread::spawn(move || {
+            accept_external(b.as_raw_fd());
+            b
+        });
+        let mut conn = DbusConnection::from_fd(a).unwrap();
+        conn.authenticate(None).unwrap();
+        assert_eq!(conn.unique_name(), Some(":1.1"));
+        assert!(conn.unix_fd_enabled());
+        let b = server.join().unwrap();
+
+        // the other end is already authenticated, as it would be when inherited
+        let mut peer = DbusConnection::from_authenticated_fd(b, true).unwrap();
+        assert!(peer.unix_fd_enabled());
+        let headers = vec![
+            Header {
+                kind: HeaderFieldKind::Path,
+                value: HeaderFieldValue::String("/org/example/Test".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Interface,
+                value: HeaderFieldValue::String("org.example.Test".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Member,
+                value: HeaderFieldValue::String("Changed".into()),
+            },
+        ];
+        conn.send(MessageType::Signal, headers, vec![], vec![])
+            .unwrap();
+        let msg = peer.read_message(None).unwrap().unwrap();
+        assert_eq!(msg.preamble.mtype, MessageType::Signal);
+
+        // only stream sockets can be used
+        let (read_end, write_end) = nix::unistd::pipe().unwrap();
+        let _write_end = unsafe { OwnedFd::from_raw_fd(write_end) };
+        let read_end = unsafe { OwnedFd::from_raw_fd(read_end) };
+        assert!(DbusConnection::from_fd(read_end).is_err());
+    }
+
+    #[test]
+    fn test_tcp_cookie_sha1() {
+        use std::os::unix::fs::DirBuilderExt;
+
+        let keyring_dir =
+            std::env::temp_dir().join(format!("dbus-native-keyring-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&keyring_dir);
+        std::fs::DirBuilder::new()
+            .mode(0o700)
+            .create(&keyring_dir)
+            .unwrap();
+        let cookie = "8a0ba4a4bd8e2d4dd6e2b3af4fe3c2e1";
+        std::fs::write(
+            keyring_dir.join("org_freedesktop_general"),
+            format!("7 1690000000 {}\n", cookie),
+        )
+        .unwrap();
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let fd = stream.as_raw_fd();
+
+            let mut nul = [1];
+            socket::recv(fd, &mut nul, socket::MsgFlags::empty()).unwrap();
+            assert_eq!(nul, [0]);
+
+            // external is tried first, which we reject as tcp has no credentials
+            assert_eq!(read_line(fd), "AUTH EXTERNAL 31303030");
+            write_line(fd, "REJECTED DBUS_COOKIE_SHA1");
+            assert_eq!(read_line(fd), "AUTH DBUS_COOKIE_SHA1 31303030");
+            let server_challenge = "0f9e5a1b3c";
+            let data = format!("org_freedesktop_general 7 {}", server_challenge);
+            write_line(fd, &format!("DATA {}", hex_encode(data.as_bytes())));
+
+            let line = read_line(fd);
+            let response = hex_decode(line.strip_prefix("DATA ").unwrap()).unwrap();
+            let response = String::from_utf8(response).unwrap();
+            let (client_challenge, hash) = response.split_once(' ').unwrap();
+            let expected =
+                sha1(format!("{}:{}:{}", server_challenge, client_challenge, cookie).as_bytes());
+            assert_eq!(hash, hex_encode(&expected));
+
+            write_line(fd, "OK 1d8a1b23e6e8c5dbb0b1b0d565a1f3f2");
+            // fds cannot be passed over tcp, so it must not be negotiated
+            assert_eq!(read_line(fd), "BEGIN");
+            reply_hello(fd);
+        });
+
+        let mut conn = DbusConnection::new(&format!("tcp:host=127.0.0.1,port={}", port)).unwrap();
+        conn.set_keyring_dir(&keyring_dir);
+        conn.authenticate(Some(1000)).unwrap();
+        assert_eq!(conn.unique_name(), Some(":1.1"));
+        assert_eq!(conn.server_guid(), Some("1d8a1b23e6e8c5dbb0b1b0d565a1f3f2"));
+
+        server.join().unwrap();
+        std::fs::remove_dir_all(&keyring_dir).unwrap();
+    }
+}
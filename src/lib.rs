@@ -0,0 +1,11 @@
+pub mod address;
+pub mod async_dbus;
+pub mod auth;
+pub mod dbus;
+pub mod message;
+pub mod names;
+pub mod proxy;
+pub mod serialize;
+pub mod server;
+pub mod shared;
+pub mod utils;
@@ -1,25 +1,18 @@
-use crate::serialize::Variant;
+use dbus_native::dbus;
+use dbus_native::serialize::Variant;
 
-mod dbus;
-mod message;
-mod proxy;
-mod serialize;
-mod utils;
 fn main() {
-    let mut dbus = dbus::DbusConnection::new("/run/user/1000/bus").unwrap();
-    dbus.authenticate(1000).unwrap();
+    let mut dbus = dbus::DbusConnection::session().unwrap();
 
-    let mut proxy = dbus.proxy(
-        "org.freedesktop.DBus".to_string(),
-        "/org/freedesktop/DBus".to_string(),
-    );
+    let mut proxy = dbus
+        .proxy("org.freedesktop.DBus", "/org/freedesktop/DBus")
+        .unwrap();
     let reply = proxy.method_call::<(), String>("org.freedesktop.DBus", "GetId", None);
     println!("{:?}", reply);
 
-    let mut proxy = dbus.proxy(
-        "org.freedesktop.systemd1".to_string(),
-        "/org/freedesktop/systemd1".to_string(),
-    );
+    let mut proxy = dbus
+        .proxy("org.freedesktop.systemd1", "/org/freedesktop/systemd1")
+        .unwrap();
     let body = (
         "org.freedesktop.systemd1.Manager".to_string(),
         "Version".to_string(),
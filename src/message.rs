@@ -1,29 +1,93 @@
 // see https://dbus.freedesktop.org/doc/dbus-specification.html and
 // https://dbus.freedesktop.org/doc/api/html/structDBusHeader.html
 
-use crate::utils::{adjust_padding, align_counter, DbusError, Result};
+use std::ops::{BitOr, BitOrAssign};
+use std::os::unix::io::OwnedFd;
 
-#[derive(Debug)]
-/// Indicates the endian of message
+use crate::names::{
+    validate_bus_name, validate_error_name, validate_interface, validate_member,
+    validate_object_path, BusName, ErrorName, InterfaceName, MemberName, ObjectPath,
+    ValidationError,
+};
+use crate::serialize::{check_body, skip_value, DbusSerialize};
+use crate::utils::{adjust_padding, align_counter, take_bytes, take_string, DbusError, Result};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Indicates the endian of message. All the numbers in the message, both in headers
+/// and in the body, are encoded in this endian
 pub enum Endian {
     Little,
-    Big, // we do not support this unless explicitly requested in youki's issues
+    Big,
 }
 
 impl Endian {
-    fn to_byte(&self) -> u8 {
+    fn to_byte(self) -> u8 {
         match self {
-            Self::Big => b'b',
+            Self::Big => b'B',
             Self::Little => b'l',
         }
     }
-    fn from_byte(byte: u8) -> Self {
+    fn from_byte(byte: u8) -> Result<Self> {
         match byte {
-            b'l' => Self::Little,
-            b'b' => Self::Big,
-            _ => panic!("invalid endian {}", byte),
+            b'l' => Ok(Self::Little),
+            b'B' => Ok(Self::Big),
+            _ => Err(DbusError::IncorrectMessage(format!(
+                "invalid endian {}",
+                byte
+            ))),
+        }
+    }
+
+    pub(crate) fn u16_bytes(self, val: u16) -> [u8; 2] {
+        match self {
+            Self::Little => val.to_le_bytes(),
+            Self::Big => val.to_be_bytes(),
+        }
+    }
+
+    pub(crate) fn u32_bytes(self, val: u32) -> [u8; 4] {
+        match self {
+            Self::Little => val.to_le_bytes(),
+            Self::Big => val.to_be_bytes(),
+        }
+    }
+
+    pub(crate) fn u64_bytes(self, val: u64) -> [u8; 8] {
+        match self {
+            Self::Little => val.to_le_bytes(),
+            Self::Big => val.to_be_bytes(),
         }
     }
+
+    // the readers take the number from given buffer at the counter, advancing it,
+    // and fail if the buffer is too short
+
+    pub(crate) fn read_u16(self, buf: &[u8], ctr: &mut usize) -> Result<u16> {
+        let mut bytes = [0; 2];
+        bytes.copy_from_slice(take_bytes(buf, ctr, 2)?);
+        Ok(match self {
+            Self::Little => u16::from_le_bytes(bytes),
+            Self::Big => u16::from_be_bytes(bytes),
+        })
+    }
+
+    pub(crate) fn read_u32(self, buf: &[u8], ctr: &mut usize) -> Result<u32> {
+        let mut bytes = [0; 4];
+        bytes.copy_from_slice(take_bytes(buf, ctr, 4)?);
+        Ok(match self {
+            Self::Little => u32::from_le_bytes(bytes),
+            Self::Big => u32::from_be_bytes(bytes),
+        })
+    }
+
+    pub(crate) fn read_u64(self, buf: &[u8], ctr: &mut usize) -> Result<u64> {
+        let mut bytes = [0; 8];
+        bytes.copy_from_slice(take_bytes(buf, ctr, 8)?);
+        Ok(match self {
+            Self::Little => u64::from_le_bytes(bytes),
+            Self::Big => u64::from_be_bytes(bytes),
+        })
+    }
 }
 
 /// Represents the type of header data
@@ -45,19 +109,22 @@ impl HeaderSignature {
             Self::U32 => b'u',
         }
     }
-    fn from_byte(byte: u8) -> Self {
+    fn from_byte(byte: u8) -> Result<Self> {
         match byte {
-            b'o' => Self::Object,
-            b'g' => Self::Signature,
-            b's' => Self::String,
-            b'u' => Self::U32,
-            _ => panic!("unexpected signature {}", byte),
+            b'o' => Ok(Self::Object),
+            b'g' => Ok(Self::Signature),
+            b's' => Ok(Self::String),
+            b'u' => Ok(Self::U32),
+            _ => Err(DbusError::IncompleteImplementation(format!(
+                "header with unsupported signature {} is sent",
+                byte as char
+            ))),
         }
     }
 }
 
 /// Type of message
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum MessageType {
     MethodCall,
     MethodReturn,
@@ -66,7 +133,7 @@ pub enum MessageType {
 }
 
 /// Represents the kind of header
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum HeaderFieldKind {
     Path,
     Interface,
@@ -76,7 +143,7 @@ pub enum HeaderFieldKind {
     Destination,
     Sender,
     BodySignature,
-    UnixFd, // we will not use this, just for the sake of completion
+    UnixFd, // number of fds sent along with the message
 }
 
 impl HeaderFieldKind {
@@ -93,21 +160,21 @@ impl HeaderFieldKind {
 
 // This is separated from header field kind, because I wanted HeaderFiledKind to be u8 like,
 // directly comparable, passable thing
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum HeaderFieldValue {
     String(String),
     U32(u32),
 }
 
 impl HeaderFieldValue {
-    fn as_bytes(&self) -> Vec<u8> {
+    fn as_bytes(&self, endian: Endian) -> Vec<u8> {
         match self {
             Self::String(s) => {
                 let mut t: Vec<u8> = s.as_bytes().into();
                 t.push(0); // null byte terminator
                 t
             }
-            Self::U32(v) => v.to_le_bytes().into(),
+            Self::U32(v) => endian.u32_bytes(*v).into(),
         }
     }
 
@@ -119,7 +186,7 @@ impl HeaderFieldValue {
     }
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Header {
     pub kind: HeaderFieldKind,
     pub value: HeaderFieldValue,
@@ -127,9 +194,12 @@ pub struct Header {
 
 impl Header {
     /// Parses a single header from given u8 vec,
-    /// assuming the header to start from given counter
-    fn parse(buf: &[u8], ctr: &mut usize) -> Result<Self> {
-        let header_kind = match buf[*ctr] {
+    /// assuming the header to start from given counter.
+    /// Headers of unknown kinds are skipped, returning None, as the spec requires
+    /// ignoring them so that new kinds can be added
+    fn parse(buf: &[u8], ctr: &mut usize, endian: Endian) -> Result<Option<Self>> {
+        let code = take_bytes(buf, ctr, 1)?[0];
+        let header_kind = match code {
             1 => HeaderFieldKind::Path,
             2 => HeaderFieldKind::Interface,
             3 => HeaderFieldKind::Member,
@@ -139,28 +209,34 @@ impl Header {
             7 => HeaderFieldKind::Sender,
             8 => HeaderFieldKind::BodySignature,
             9 => HeaderFieldKind::UnixFd,
-            _ => panic!("invalid header kind"), // should not occur unless we screw up parsing somewhere else
+            0 => {
+                return Err(DbusError::IncorrectMessage(
+                    "header of invalid kind 0 is sent".into(),
+                ))
+            }
+            _ => {
+                // the value is a variant, so it can be skipped even without knowing its type
+                let signature_length = take_bytes(buf, ctr, 1)?[0] as usize;
+                let signature = take_string(buf, ctr, signature_length)?;
+                skip_value(buf, ctr, &signature, endian)?;
+                return Ok(None);
+            }
         };
 
-        // account for the header_kind byte
-        *ctr += 1;
-
         // length of signature is always <255
-        let signature_length = buf[*ctr] as usize;
-        *ctr += 1;
+        let signature_length = take_bytes(buf, ctr, 1)?[0] as usize;
 
         // we only support string, u32 signature and object,
         // all of which have signature of 1 byte
         if signature_length != 1 {
-            return Err(DbusError::IncompleteImplementation(format!(
-                "some complex valued header is sent"
-            )));
+            return Err(DbusError::IncompleteImplementation(
+                "some complex valued header is sent".to_string(),
+            ));
         }
 
-        let actual_signature = HeaderSignature::from_byte(buf[*ctr]);
-
-        // we can simply += 1, but I think this is more sensible
-        *ctr += signature_length;
+        // as checked above, this is a single byte followed by a null byte
+        let signature = take_bytes(buf, ctr, 2)?;
+        let actual_signature = HeaderSignature::from_byte(signature[0])?;
 
         let expected_signature = header_kind.signature();
 
@@ -171,60 +247,121 @@ impl Header {
             )));
         }
 
-        *ctr += 1; // accounting for extra null byte that is always there
-
         let value = match expected_signature {
-            HeaderSignature::U32 => {
-                let ret = HeaderFieldValue::U32(u32::from_le_bytes(
-                    buf[*ctr..*ctr + 4].try_into().unwrap(), // we ca unwrap here as we know 4 byte buffer will satisfy [u8;4]
-                ));
-                *ctr += 4;
-                ret
-            }
+            HeaderSignature::U32 => HeaderFieldValue::U32(endian.read_u32(buf, ctr)?),
             // both are encoded as string
             HeaderSignature::Object | HeaderSignature::String => {
-                let len = u32::from_le_bytes(buf[*ctr..*ctr + 4].try_into().unwrap()) as usize;
-                *ctr += 4;
-                let string = String::from_utf8(buf[*ctr..*ctr + len].into()).unwrap();
-                *ctr += len + 1; // +1 to account for null
-                HeaderFieldValue::String(string)
+                let len = endian.read_u32(buf, ctr)? as usize;
+                HeaderFieldValue::String(take_string(buf, ctr, len)?)
             }
             // only difference here is that length is 1 byte, not 4 bytes
             HeaderSignature::Signature => {
-                let len = buf[*ctr] as usize;
-                *ctr += 1;
-                let signature = String::from_utf8(buf[*ctr..*ctr + len].into()).unwrap();
-                *ctr += len + 1; //+1 to account for null byte
-                HeaderFieldValue::String(signature)
+                let len = take_bytes(buf, ctr, 1)?[0] as usize;
+                HeaderFieldValue::String(take_string(buf, ctr, len)?)
             }
         };
-        Ok(Self {
+        Ok(Some(Self {
             kind: header_kind,
             value,
-        })
+        }))
+    }
+}
+
+/// Flags set on a message, which can be combined using `|`.
+/// Flags which are not known are kept as they are, as the spec requires ignoring them
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MessageFlags(u8);
+
+impl MessageFlags {
+    /// The sender does not want a reply to this method call, so none should be sent
+    pub const NO_REPLY_EXPECTED: Self = Self(0x1);
+    /// The bus must not start the owner of the destination name to deliver this message
+    pub const NO_AUTO_START: Self = Self(0x2);
+    /// The caller is prepared to wait for an interactive authorization prompt
+    pub const ALLOW_INTERACTIVE_AUTHORIZATION: Self = Self(0x4);
+
+    /// No flags set
+    pub const fn empty() -> Self {
+        Self(0)
+    }
+
+    /// Flags as they are encoded in the message
+    pub const fn bits(self) -> u8 {
+        self.0
+    }
+
+    /// Flags from the byte encoded in a message
+    pub const fn from_bits(bits: u8) -> Self {
+        Self(bits)
+    }
+
+    /// Check if all of given flags are set
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub fn insert(&mut self, other: Self) {
+        self.0 |= other.0;
+    }
+
+    pub fn remove(&mut self, other: Self) {
+        self.0 &= !other.0;
+    }
+}
+
+impl BitOr for MessageFlags {
+    type Output = Self;
+    fn bitor(self, other: Self) -> Self {
+        Self(self.0 | other.0)
+    }
+}
+
+impl BitOrAssign for MessageFlags {
+    fn bitor_assign(&mut self, other: Self) {
+        self.0 |= other.0;
     }
 }
 
 /// Message preamble of initial 4 bytes
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct Preamble {
     endian: Endian,
     pub mtype: MessageType,
-    flags: u8,
+    flags: MessageFlags,
     version: u8,
 }
 
 impl Preamble {
+    /// Get the endian the message is encoded in
+    pub fn endian(&self) -> Endian {
+        self.endian
+    }
+
+    /// Get the flags set on the message
+    pub fn flags(&self) -> MessageFlags {
+        self.flags
+    }
+
     fn new(mtype: MessageType) -> Self {
         Self {
             endian: Endian::Little,
             mtype,
-            flags: 0,   // until we need some flags to be used, this is fixed
+            flags: MessageFlags::empty(),
             version: 1, // this is fixed until dbus releases a new major version
         }
     }
 }
 
+/// Length of the fixed part at start of every message :
+/// preamble, body length, serial and header array length
+pub const FIXED_HEADER_LENGTH: usize = 16;
+
+/// Maximum length of a complete message, including its headers
+pub const MAX_MESSAGE_LENGTH: usize = 1 << 27;
+
+/// Maximum length of an array in bytes
+pub const MAX_ARRAY_LENGTH: usize = 1 << 26;
+
 /// Represents a complete message transported over dbus connection
 #[derive(Debug)]
 pub struct Message {
@@ -236,9 +373,28 @@ pub struct Message {
     pub headers: Vec<Header>,
     /// Actual body, serialized
     pub body: Vec<u8>,
+    /// File descriptors sent along with the message, the body
+    /// refers to these by their index
+    pub fds: Vec<OwnedFd>,
 }
 
 impl Message {
+    /// Get the value of header of given kind, if the message has it
+    pub fn get_header(&self, kind: &HeaderFieldKind) -> Option<&HeaderFieldValue> {
+        self.headers
+            .iter()
+            .find(|h| h.kind == *kind)
+            .map(|h| &h.value)
+    }
+
+    /// Get the serial of the message this message is a reply to, if it is a reply
+    pub fn reply_serial(&self) -> Option<u32> {
+        match self.get_header(&HeaderFieldKind::ReplySerial) {
+            Some(HeaderFieldValue::U32(serial)) => Some(*serial),
+            _ => None,
+        }
+    }
+
     pub fn new(mtype: MessageType, serial: u32, headers: Vec<Header>, body: Vec<u8>) -> Self {
         let preamble = Preamble::new(mtype);
         Self {
@@ -246,14 +402,253 @@ impl Message {
             serial,
             headers,
             body,
+            fds: vec![],
+        }
+    }
+
+    /// Create a copy of the message, with its own duplicates of the fds.
+    /// This fails if the fds cannot be duplicated, such as when we are out of fds
+    pub(crate) fn try_clone(&self) -> Result<Message> {
+        let fds = self
+            .fds
+            .iter()
+            .map(|fd| fd.try_clone())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        Ok(Message {
+            preamble: self.preamble.clone(),
+            serial: self.serial,
+            headers: self.headers.clone(),
+            body: self.body.clone(),
+            fds,
+        })
+    }
+
+    /// Create a method call to given member of the interface at given path of the destination,
+    /// which can be given as strings or as names, and are checked to be valid.
+    /// The serial is assigned by the connection when the message is sent
+    pub fn method_call(
+        destination: impl TryInto<BusName, Error = DbusError>,
+        path: impl TryInto<ObjectPath, Error = DbusError>,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+    ) -> Result<Self> {
+        let headers = vec![
+            Header {
+                kind: HeaderFieldKind::Path,
+                value: HeaderFieldValue::String(path.try_into()?.into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Destination,
+                value: HeaderFieldValue::String(destination.try_into()?.into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Interface,
+                value: HeaderFieldValue::String(interface.try_into()?.into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Member,
+                value: HeaderFieldValue::String(member.try_into()?.into()),
+            },
+        ];
+        Ok(Self::new(MessageType::MethodCall, 0, headers, vec![]))
+    }
+
+    /// Create a reply to given method call, sent back to its sender
+    pub fn method_return(call: &Message) -> Self {
+        Self::new(
+            MessageType::MethodReturn,
+            0,
+            Self::reply_headers(call),
+            vec![],
+        )
+        .with_endian(call.preamble.endian)
+    }
+
+    /// Create an error reply with given error name to given method call,
+    /// with the text describing the error as its body
+    pub fn error(
+        call: &Message,
+        name: impl TryInto<ErrorName, Error = DbusError>,
+        text: &str,
+    ) -> Result<Self> {
+        let mut headers = Self::reply_headers(call);
+        headers.push(Header {
+            kind: HeaderFieldKind::ErrorName,
+            value: HeaderFieldValue::String(name.try_into()?.into()),
+        });
+        let msg =
+            Self::new(MessageType::Error, 0, headers, vec![]).with_endian(call.preamble.endian);
+        let mut body = vec![];
+        text.to_string()
+            .serialize_endian(&mut body, &mut vec![], msg.preamble.endian);
+        Ok(msg.with_serialized_body("s".into(), body))
+    }
+
+    /// Create a signal with given member of the interface, emitted from given path
+    pub fn signal(
+        path: impl TryInto<ObjectPath, Error = DbusError>,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+    ) -> Result<Self> {
+        let headers = vec![
+            Header {
+                kind: HeaderFieldKind::Path,
+                value: HeaderFieldValue::String(path.try_into()?.into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Interface,
+                value: HeaderFieldValue::String(interface.try_into()?.into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Member,
+                value: HeaderFieldValue::String(member.try_into()?.into()),
+            },
+        ];
+        Ok(Self::new(MessageType::Signal, 0, headers, vec![]))
+    }
+
+    /// Headers of a reply to given call, which is sent back to the sender of the call
+    fn reply_headers(call: &Message) -> Vec<Header> {
+        let mut headers = vec![Header {
+            kind: HeaderFieldKind::ReplySerial,
+            value: HeaderFieldValue::U32(call.serial),
+        }];
+        if let Some(HeaderFieldValue::String(sender)) = call.get_header(&HeaderFieldKind::Sender) {
+            headers.push(Header {
+                kind: HeaderFieldKind::Destination,
+                value: HeaderFieldValue::String(sender.clone()),
+            });
+        }
+        headers
+    }
+
+    /// Set the body of the message, serialized in the endian of the message,
+    /// along with the body signature header, so the endian must be set before this.
+    /// Any fds in the body are duplicated, so the message owns them
+    pub fn with_body<Body: DbusSerialize>(self, body: &Body) -> Result<Self> {
+        let mut serialized = vec![];
+        let mut borrowed_fds = vec![];
+        body.serialize_endian(&mut serialized, &mut borrowed_fds, self.preamble.endian);
+        let fds = borrowed_fds
+            .iter()
+            .map(|fd| fd.try_clone_to_owned())
+            .collect::<std::io::Result<Vec<_>>>()?;
+        let mut msg = self.with_serialized_body(Body::get_signature(), serialized);
+        msg.fds = fds;
+        Ok(msg)
+    }
+
+    /// Set the already serialized body of given signature, replacing any previous body
+    fn with_serialized_body(mut self, signature: String, body: Vec<u8>) -> Self {
+        self.headers
+            .retain(|h| h.kind != HeaderFieldKind::BodySignature);
+        // there is no signature header for an empty body
+        if !signature.is_empty() {
+            self.headers.push(Header {
+                kind: HeaderFieldKind::BodySignature,
+                value: HeaderFieldValue::String(signature),
+            });
         }
+        self.body = body;
+        self
+    }
+
+    /// Set the endian the message is to be encoded in, which is little endian by default.
+    /// Only the headers are encoded by `serialize`, so the body must have been
+    /// serialized in the same endian using `DbusSerialize::serialize_endian`
+    pub fn with_endian(mut self, endian: Endian) -> Self {
+        self.preamble.endian = endian;
+        self
+    }
+
+    /// Set the flags of the message, which has none by default
+    pub fn with_flags(mut self, flags: MessageFlags) -> Self {
+        self.preamble.flags = flags;
+        self
+    }
+
+    /// Check that a reply to the message can be waited for, which is not the case
+    /// if it has `NO_REPLY_EXPECTED`
+    pub(crate) fn check_reply_expected(&self) -> Result<()> {
+        if self
+            .preamble
+            .flags
+            .contains(MessageFlags::NO_REPLY_EXPECTED)
+        {
+            return Err(ValidationError::ReplyNotExpected.into());
+        }
+        Ok(())
+    }
+
+    /// Check that the body matches the body signature header, and that none of
+    /// its arrays are longer than the spec allows
+    pub(crate) fn check_body(&self) -> Result<()> {
+        let signature = match self.get_header(&HeaderFieldKind::BodySignature) {
+            Some(HeaderFieldValue::String(s)) => s.as_str(),
+            _ => "",
+        };
+        check_body(&self.body, signature, self.preamble.endian)
+    }
+
+    /// Check that the message is valid according to the spec : it must have the headers
+    /// required for its type, none of them more than once, and the names in them must be valid
+    pub fn validate(&self) -> Result<()> {
+        let mut seen = Vec::with_capacity(self.headers.len());
+        for header in &self.headers {
+            if seen.contains(&&header.kind) {
+                return Err(ValidationError::DuplicateHeader(header.kind.clone()).into());
+            }
+            seen.push(&header.kind);
+
+            match (&header.kind, &header.value) {
+                (
+                    HeaderFieldKind::ReplySerial | HeaderFieldKind::UnixFd,
+                    HeaderFieldValue::U32(_),
+                ) => {}
+                (HeaderFieldKind::Path, HeaderFieldValue::String(s)) => validate_object_path(s)?,
+                (HeaderFieldKind::Interface, HeaderFieldValue::String(s)) => validate_interface(s)?,
+                (HeaderFieldKind::Member, HeaderFieldValue::String(s)) => validate_member(s)?,
+                (HeaderFieldKind::ErrorName, HeaderFieldValue::String(s)) => {
+                    validate_error_name(s)?
+                }
+                (
+                    HeaderFieldKind::Destination | HeaderFieldKind::Sender,
+                    HeaderFieldValue::String(s),
+                ) => validate_bus_name(s)?,
+                // length of signature is encoded in a single byte
+                (HeaderFieldKind::BodySignature, HeaderFieldValue::String(s)) if s.len() <= 255 => {
+                }
+                (kind, _) => return Err(ValidationError::InvalidHeaderValue(kind.clone()).into()),
+            }
+        }
+
+        let required: &[HeaderFieldKind] = match self.preamble.mtype {
+            MessageType::MethodCall => &[HeaderFieldKind::Path, HeaderFieldKind::Member],
+            MessageType::MethodReturn => &[HeaderFieldKind::ReplySerial],
+            MessageType::Error => &[HeaderFieldKind::ErrorName, HeaderFieldKind::ReplySerial],
+            MessageType::Signal => &[
+                HeaderFieldKind::Path,
+                HeaderFieldKind::Interface,
+                HeaderFieldKind::Member,
+            ],
+        };
+        for kind in required {
+            if !seen.contains(&kind) {
+                return Err(ValidationError::MissingHeader {
+                    mtype: self.preamble.mtype.clone(),
+                    kind: kind.clone(),
+                }
+                .into());
+            }
+        }
+        Ok(())
     }
 }
 
 // NOTE that this does not add padding after last header, because we need
 // non-padded header length
 // This alignment must be done  separately after this
-fn serialize_headers(headers: &[Header]) -> Vec<u8> {
+fn serialize_headers(headers: &[Header], endian: Endian) -> Vec<u8> {
     let mut ret = vec![];
 
     for header in headers {
@@ -289,32 +684,33 @@ fn serialize_headers(headers: &[Header]) -> Vec<u8> {
             }
             HeaderFieldKind::ReplySerial | HeaderFieldKind::UnixFd => { /* do nothing */ }
             _ => {
-                ret.extend_from_slice(&header_value_length.to_le_bytes());
+                ret.extend_from_slice(&endian.u32_bytes(header_value_length));
             }
         }
 
-        ret.extend_from_slice(&header.value.as_bytes());
+        ret.extend_from_slice(&header.value.as_bytes(endian));
     }
 
     ret
 }
 
-fn deserialize_headers(buf: &[u8]) -> Result<Vec<Header>> {
+fn deserialize_headers(buf: &[u8], endian: Endian) -> Result<Vec<Header>> {
     let mut ret = Vec::new();
 
     let mut ctr = 0;
     // headers are always aligned at 8 byte boundary
     align_counter(&mut ctr, 8);
     while ctr < buf.len() {
-        let header = Header::parse(buf, &mut ctr)?;
+        let header = Header::parse(buf, &mut ctr, endian)?;
         align_counter(&mut ctr, 8);
-        ret.push(header);
+        ret.extend(header);
     }
     Ok(ret)
 }
 
 impl Message {
     /// Serialize the given message into u8 vec
+    /// Note that the fds are not part of the serialized message, and must be sent separately
     pub fn serialize(mut self) -> Vec<u8> {
         let mtype = match self.preamble.mtype {
             MessageType::MethodCall => 1,
@@ -328,20 +724,22 @@ impl Message {
         let mut message = vec![
             self.preamble.endian.to_byte(),
             mtype,
-            self.preamble.flags,
+            self.preamble.flags.bits(),
             self.preamble.version,
         ];
 
+        let endian = self.preamble.endian;
+
         // set body length
-        message.extend_from_slice(&(self.body.len() as u32).to_le_bytes());
+        message.extend_from_slice(&endian.u32_bytes(self.body.len() as u32));
 
         // set id
-        message.extend_from_slice(&self.serial.to_le_bytes());
+        message.extend_from_slice(&endian.u32_bytes(self.serial));
 
-        let serialized_headers = serialize_headers(&self.headers);
+        let serialized_headers = serialize_headers(&self.headers, endian);
 
         // header length -  to be calculated without padding
-        message.extend_from_slice(&(serialized_headers.len() as u32).to_le_bytes());
+        message.extend_from_slice(&endian.u32_bytes(serialized_headers.len() as u32));
         message.extend_from_slice(&serialized_headers);
 
         adjust_padding(&mut message, 8);
@@ -354,59 +752,550 @@ impl Message {
         message
     }
 
-    pub fn deserialize(buf: &[u8], counter: &mut usize) -> Result<Self> {
-        let endian = Endian::from_byte(buf[*counter]);
+    /// Get the total length of the message starting at the beginning of given buffer.
+    /// This is known from the fixed header alone, so we return None only if
+    /// the buffer does not contain the complete fixed header yet
+    pub fn total_length(buf: &[u8]) -> Option<usize> {
+        if buf.len() < FIXED_HEADER_LENGTH {
+            return None;
+        }
+        // an invalid endian is reported when the message is deserialized,
+        // until then treat it as little endian
+        let endian = Endian::from_byte(buf[0]).unwrap_or(Endian::Little);
+        // these cannot fail, as we have checked the length above
+        let body_length = endian.read_u32(buf, &mut 4).ok()? as usize;
+        let header_array_length = endian.read_u32(buf, &mut 12).ok()? as usize;
 
-        if !matches!(endian, Endian::Little) {
-            return Err(DbusError::IncompleteImplementation(
-                "we do not support big endian yet".into(),
-            ));
+        // body starts at 8 byte boundary after the headers
+        let mut length = FIXED_HEADER_LENGTH + header_array_length;
+        align_counter(&mut length, 8);
+        Some(length + body_length)
+    }
+
+    /// Number of fds declared by the UnixFd header of the message at the start of given
+    /// buffer, reading only the headers, so it can be known even for a message which
+    /// is not valid. Returns None if the headers themselves cannot be read
+    pub(crate) fn declared_fds(buf: &[u8]) -> Option<usize> {
+        let endian = Endian::from_byte(*buf.first()?).ok()?;
+        let header_array_length = endian.read_u32(buf, &mut 12).ok()? as usize;
+        let headers =
+            take_bytes(buf, &mut FIXED_HEADER_LENGTH.clone(), header_array_length).ok()?;
+        let fds = deserialize_headers(headers, endian)
+            .ok()?
+            .into_iter()
+            .find_map(|h| match (h.kind, h.value) {
+                (HeaderFieldKind::UnixFd, HeaderFieldValue::U32(n)) => Some(n as usize),
+                _ => None,
+            });
+        Some(fds.unwrap_or(0))
+    }
+
+    /// Deserialize the message starting at the counter in given buffer,
+    /// checking that it is valid according to the spec
+    pub fn deserialize(buf: &[u8], counter: &mut usize) -> Result<Self> {
+        if let Some(length) = Message::total_length(&buf[(*counter).min(buf.len())..]) {
+            if length > MAX_MESSAGE_LENGTH {
+                return Err(ValidationError::MessageTooLong(length).into());
+            }
         }
 
-        let mtype = match buf[*counter + 1] {
+        let bytes = take_bytes(buf, counter, 4)?;
+        let endian = Endian::from_byte(bytes[0])?;
+
+        let mtype = match bytes[1] {
             1 => MessageType::MethodCall,
             2 => MessageType::MethodReturn,
             3 => MessageType::Error,
             4 => MessageType::Signal,
-            _ => panic!("invalid message type {}", buf[*counter + 1]),
+            mtype => {
+                return Err(DbusError::IncorrectMessage(format!(
+                    "invalid message type {}",
+                    mtype
+                )))
+            }
         };
 
-        let _flags = buf[*counter + 2]; // we basically ignore flags
-        let version = buf[*counter + 3];
+        let flags = MessageFlags::from_bits(bytes[2]);
+        let version = bytes[3];
 
         if version != 1 {
-            panic!("when did dbus release new version?!?!?!");
+            return Err(DbusError::IncompleteImplementation(format!(
+                "unsupported protocol version {}",
+                version
+            )));
         }
 
-        *counter += 4; // account for preamble bytes
-
-        let preamble = Preamble::new(mtype);
-
-        let body_length =
-            u32::from_le_bytes(buf[*counter..*counter + 4].try_into().unwrap()) as usize;
-        *counter += 4;
-
-        let serial = u32::from_le_bytes(buf[*counter..*counter + 4].try_into().unwrap());
-        *counter += 4;
+        // keep the preamble as received, so the message is encoded the same
+        // if it is serialized again, such as when proxying it
+        let preamble = Preamble {
+            endian,
+            mtype,
+            flags,
+            version,
+        };
 
-        let header_array_length =
-            u32::from_le_bytes(buf[*counter..*counter + 4].try_into().unwrap()) as usize;
-        *counter += 4;
+        let body_length = endian.read_u32(buf, counter)? as usize;
+        let serial = endian.read_u32(buf, counter)?;
+        let header_array_length = endian.read_u32(buf, counter)? as usize;
 
-        let headers = deserialize_headers(&buf[*counter..*counter + header_array_length])?;
-        *counter += header_array_length;
-        align_counter(counter, 8);
+        let headers = deserialize_headers(take_bytes(buf, counter, header_array_length)?, endian)?;
+        // the padding after headers is there even if the body is empty
+        let mut body_start = *counter;
+        align_counter(&mut body_start, 8);
+        take_bytes(buf, counter, body_start - *counter)?;
 
         // we do not deserialize body here, and istead let the caller do it as needed
         // that way we don't have do deal with error checking or validating the body signature etc
-        let body = Vec::from(&buf[*counter..*counter + body_length]);
-        *counter += body_length;
+        let body = take_bytes(buf, counter, body_length)?.to_vec();
 
-        Ok(Self {
+        // fds are received out of band, so the connection must attach them
+        let msg = Self {
             preamble,
             serial,
             headers,
             body,
-        })
+            fds: vec![],
+        };
+        msg.validate()?;
+        Ok(msg)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::serialize::{DbusSerialize, Variant};
+
+    #[test]
+    fn test_flags() {
+        // reply to hello from raw-decoded/hello_reply.txt, which has NO_REPLY_EXPECTED set
+        let bytes = b"l\x02\x01\x01\x0c\x00\x00\x00\xff\xff\xff\xff?\x00\x00\x00\x05\x01u\x00\x01\x00\x00\x00\x07\x01s\x00\x14\x00\x00\x00org.freedesktop.DBus\x00\x00\x00\x00\x06\x01s\x00\x07\x00\x00\x00:1.2072\x00\x08\x01g\x00\x01s\x00\x00\x07\x00\x00\x00:1.2072\x00";
+        let mut ctr = 0;
+        let msg = Message::deserialize(bytes, &mut ctr).unwrap();
+        assert_eq!(ctr, bytes.len());
+        let flags = msg.preamble.flags();
+        assert!(flags.contains(MessageFlags::NO_REPLY_EXPECTED));
+        assert!(!flags.contains(MessageFlags::NO_AUTO_START));
+        assert_eq!(msg.serialize(), bytes);
+
+        let mut flags = MessageFlags::NO_AUTO_START | MessageFlags::ALLOW_INTERACTIVE_AUTHORIZATION;
+        assert_eq!(flags.bits(), 0x6);
+        flags.remove(MessageFlags::NO_AUTO_START);
+        flags |= MessageFlags::NO_REPLY_EXPECTED;
+        assert_eq!(flags.bits(), 0x5);
+        let bytes = Message::new(MessageType::MethodCall, 1, vec![], vec![])
+            .with_flags(flags)
+            .serialize();
+        assert_eq!(bytes[2], 0x5);
+        // unknown flags are kept as they are
+        assert_eq!(MessageFlags::from_bits(0x80).bits(), 0x80);
+    }
+
+    #[test]
+    fn test_big_endian() {
+        // method return with reply serial 9, as sent by a big endian peer
+        let bytes = [
+            b'B', 2, 0, 1, // preamble
+            0, 0, 0, 0, // body length
+            0, 0, 0, 5, // serial
+            0, 0, 0, 8, // header array length
+            5, 1, b'u', 0, 0, 0, 0, 9, // reply serial header
+        ];
+        assert_eq!(Message::total_length(&bytes), Some(bytes.len()));
+        let mut ctr = 0;
+        let msg = Message::deserialize(&bytes, &mut ctr).unwrap();
+        assert_eq!(ctr, bytes.len());
+        assert_eq!(msg.preamble.endian(), Endian::Big);
+        assert_eq!(msg.serial, 5);
+        assert_eq!(msg.reply_serial(), Some(9));
+        // serializing again gives the same bytes
+        assert_eq!(msg.serialize(), bytes);
+
+        let mut body = vec![];
+        (String::from("hello"), 42_u32).serialize_endian(&mut body, &mut vec![], Endian::Big);
+        assert_eq!(&body[..4], &[0, 0, 0, 5]);
+        let headers = vec![
+            Header {
+                kind: HeaderFieldKind::Path,
+                value: HeaderFieldValue::String("/org/example".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Interface,
+                value: HeaderFieldValue::String("org.example".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Member,
+                value: HeaderFieldValue::String("Changed".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::BodySignature,
+                value: HeaderFieldValue::String("su".into()),
+            },
+        ];
+        let bytes = Message::new(MessageType::Signal, 0x0102, headers, body)
+            .with_endian(Endian::Big)
+            .serialize();
+        assert_eq!(&bytes[..12], &[b'B', 4, 0, 1, 0, 0, 0, 16, 0, 0, 1, 2]);
+
+        let mut ctr = 0;
+        let msg = Message::deserialize(&bytes, &mut ctr).unwrap();
+        assert_eq!(msg.serial, 0x0102);
+        assert!(matches!(
+            msg.get_header(&HeaderFieldKind::Path),
+            Some(HeaderFieldValue::String(p)) if p == "/org/example"
+        ));
+        let (s, n) =
+            <(String, u32)>::deserialize_endian(&msg.body, &mut 0, &mut [], msg.preamble.endian())
+                .unwrap();
+        assert_eq!(s, "hello");
+        assert_eq!(n, 42);
+
+        // only l and B are valid endian markers
+        let mut bytes = bytes;
+        bytes[0] = b'b';
+        assert!(Message::deserialize(&bytes, &mut 0).is_err());
+    }
+
+    #[test]
+    fn test_malformed() {
+        let mut body = vec![];
+        (String::from("hello"), 42_u32).serialize(&mut body, &mut vec![]);
+        let headers = vec![
+            Header {
+                kind: HeaderFieldKind::Path,
+                value: HeaderFieldValue::String("/org/example".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Interface,
+                value: HeaderFieldValue::String("org.example".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::Member,
+                value: HeaderFieldValue::String("Changed".into()),
+            },
+            Header {
+                kind: HeaderFieldKind::BodySignature,
+                value: HeaderFieldValue::String("su".into()),
+            },
+        ];
+        let bytes = Message::new(MessageType::Signal, 1, headers, body).serialize();
+
+        // every truncation fails, without panicking
+        for len in 0..bytes.len() {
+            assert!(Message::deserialize(&bytes[..len], &mut 0).is_err());
+        }
+        // as does every possible corruption of any single byte
+        for i in 0..bytes.len() {
+            for b in 0..=255 {
+                let mut corrupted = bytes.clone();
+                corrupted[i] = b;
+                let _ = Message::deserialize(&corrupted, &mut 0);
+            }
+        }
+
+        let corrupt = |i: usize, b: u8| {
+            let mut corrupted = bytes.clone();
+            corrupted[i] = b;
+            Message::deserialize(&corrupted, &mut 0).unwrap_err()
+        };
+        // message type
+        assert!(matches!(corrupt(1, 5), DbusError::IncorrectMessage(_)));
+        // protocol version
+        assert!(matches!(
+            corrupt(3, 2),
+            DbusError::IncompleteImplementation(_)
+        ));
+        // header kind, where unknown kinds are skipped, leaving the path missing
+        assert!(matches!(corrupt(16, 0), DbusError::IncorrectMessage(_)));
+        assert!(matches!(
+            corrupt(16, 0xff),
+            DbusError::ValidationError(ValidationError::MissingHeader { .. })
+        ));
+        // header signature
+        assert!(matches!(
+            corrupt(18, b'x'),
+            DbusError::IncompleteImplementation(_)
+        ));
+        // invalid utf-8 in the path
+        assert!(matches!(corrupt(24, 0xff), DbusError::IncorrectMessage(_)));
+        // missing null after the path
+        assert!(matches!(corrupt(36, b'x'), DbusError::IncorrectMessage(_)));
+
+        // the body is checked when deserialized
+        let mut ctr = 0;
+        let msg = Message::deserialize(&bytes, &mut ctr).unwrap();
+        for len in 0..msg.body.len() {
+            assert!(<(String, u32)>::deserialize(&msg.body[..len], &mut 0, &mut []).is_err());
+        }
+        assert!(Variant::<u32>::deserialize(b"\x01s\0", &mut 0, &mut []).is_err());
+        assert!(bool::deserialize(&[2, 0, 0, 0], &mut 0, &mut []).is_err());
+        assert!(OwnedFd::deserialize(&[0, 0, 0, 0], &mut 0, &mut []).is_err());
+        // an array longer than the whole buffer
+        assert!(Vec::<u32>::deserialize(&[0xff, 0, 0, 0], &mut 0, &mut []).is_err());
+    }
+
+    #[test]
+    fn test_unknown_header() {
+        let mut signal = Message::signal("/org/example", "org.example", "Changed").unwrap();
+        signal.serial = 1;
+        let mut bytes = signal.serialize();
+        let start = bytes.len();
+        // a header of kind 0x20 with an array of structs, each holding a string and a variant
+        bytes.extend_from_slice(&[0x20, 5, b'a', b'(', b's', b'v', b')', 0]);
+        bytes.extend_from_slice(&[16, 0, 0, 0, 0, 0, 0, 0]);
+        bytes.extend_from_slice(&[1, 0, 0, 0, b'k', 0, 1, b'u', 0, 0, 0, 0, 42, 0, 0, 0]);
+        let header_length = (bytes.len() - FIXED_HEADER_LENGTH) as u32;
+        bytes[12..16].copy_from_slice(&header_length.to_le_bytes());
+
+        let mut ctr = 0;
+        let msg = Message::deserialize(&bytes, &mut ctr).unwrap();
+        assert_eq!(ctr, bytes.len());
+        assert_eq!(msg.headers.len(), 3);
+        assert!(matches!(
+            msg.get_header(&HeaderFieldKind::Member),
+            Some(HeaderFieldValue::String(m)) if m == "Changed"
+        ));
+
+        // the value of an unknown header is still checked to be within the message
+        let mut truncated = bytes.clone();
+        truncated[start + 8] = 24;
+        assert!(Message::deserialize(&truncated, &mut 0).is_err());
+        let mut invalid = bytes;
+        invalid[start + 3] = b'z';
+        assert!(Message::deserialize(&invalid, &mut 0).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        let header = |kind: HeaderFieldKind, value: &str| Header {
+            kind,
+            value: HeaderFieldValue::String(value.into()),
+        };
+        let reply_serial = Header {
+            kind: HeaderFieldKind::ReplySerial,
+            value: HeaderFieldValue::U32(1),
+        };
+        let error = |mtype: MessageType, headers: Vec<Header>| match Message::new(
+            mtype,
+            1,
+            headers,
+            vec![],
+        )
+        .validate()
+        {
+            Err(DbusError::ValidationError(e)) => e,
+            res => panic!("expected validation error, got {:?}", res),
+        };
+
+        let call = vec![
+            header(HeaderFieldKind::Path, "/org/example"),
+            header(HeaderFieldKind::Member, "Get"),
+            header(HeaderFieldKind::Destination, "org.example"),
+        ];
+        assert!(
+            Message::new(MessageType::MethodCall, 1, call.clone(), vec![])
+                .validate()
+                .is_ok()
+        );
+        assert_eq!(
+            error(MessageType::MethodCall, call[1..].to_vec()),
+            ValidationError::MissingHeader {
+                mtype: MessageType::MethodCall,
+                kind: HeaderFieldKind::Path
+            }
+        );
+        assert_eq!(
+            error(MessageType::Signal, call.clone()),
+            ValidationError::MissingHeader {
+                mtype: MessageType::Signal,
+                kind: HeaderFieldKind::Interface
+            }
+        );
+        assert_eq!(
+            error(MessageType::Error, vec![reply_serial.clone()]),
+            ValidationError::MissingHeader {
+                mtype: MessageType::Error,
+                kind: HeaderFieldKind::ErrorName
+            }
+        );
+        assert_eq!(
+            error(
+                MessageType::MethodReturn,
+                vec![reply_serial.clone(), reply_serial.clone()]
+            ),
+            ValidationError::DuplicateHeader(HeaderFieldKind::ReplySerial)
+        );
+        assert_eq!(
+            error(
+                MessageType::MethodReturn,
+                vec![header(HeaderFieldKind::ReplySerial, "1")]
+            ),
+            ValidationError::InvalidHeaderValue(HeaderFieldKind::ReplySerial)
+        );
+
+        let mut invalid = call.clone();
+        invalid[0] = header(HeaderFieldKind::Path, "org/example");
+        assert!(matches!(
+            error(MessageType::MethodCall, invalid),
+            ValidationError::InvalidObjectPath(_)
+        ));
+        let mut invalid = call.clone();
+        invalid[1] = header(HeaderFieldKind::Member, "Get.All");
+        assert!(matches!(
+            error(MessageType::MethodCall, invalid),
+            ValidationError::InvalidMember(_)
+        ));
+        let mut invalid = call;
+        invalid[2] = header(HeaderFieldKind::Destination, "org");
+        assert!(matches!(
+            error(MessageType::MethodCall, invalid),
+            ValidationError::InvalidBusName(_)
+        ));
+
+        // invalid messages are rejected when decoding as well
+        let bytes = Message::new(MessageType::MethodReturn, 1, vec![], vec![]).serialize();
+        assert!(matches!(
+            Message::deserialize(&bytes, &mut 0),
+            Err(DbusError::ValidationError(
+                ValidationError::MissingHeader { .. }
+            ))
+        ));
+        let mut bytes = bytes;
+        bytes[4..8].copy_from_slice(&(MAX_MESSAGE_LENGTH as u32).to_le_bytes());
+        assert!(matches!(
+            Message::deserialize(&bytes, &mut 0),
+            Err(DbusError::ValidationError(ValidationError::MessageTooLong(
+                _
+            )))
+        ));
+    }
+
+    #[test]
+    fn test_array_length() {
+        // length is in bytes, without the padding before the first element
+        let mut buf = vec![];
+        vec![1_u64, 2].serialize(&mut buf, &mut vec![]);
+        assert_eq!(buf.len(), 24);
+        assert_eq!(&buf[..8], &[16, 0, 0, 0, 0, 0, 0, 0]);
+        assert_eq!(
+            Vec::<u64>::deserialize(&buf, &mut 0, &mut []).unwrap(),
+            vec![1, 2]
+        );
+
+        // the padding is there even for empty arrays
+        let mut buf = vec![];
+        Vec::<u64>::new().serialize(&mut buf, &mut vec![]);
+        assert_eq!(buf, vec![0; 8]);
+
+        let mut buf = vec![];
+        vec!["a".to_string(), "bc".to_string()].serialize(&mut buf, &mut vec![]);
+        assert_eq!(buf[0], 15);
+        let mut ctr = 0;
+        let strings = Vec::<String>::deserialize(&buf, &mut ctr, &mut []).unwrap();
+        assert_eq!(strings, vec!["a", "bc"]);
+        assert_eq!(ctr, buf.len());
+
+        // elements must end exactly at the end of the array
+        buf[0] = 14;
+        assert!(Vec::<String>::deserialize(&buf, &mut 0, &mut []).is_err());
+        assert!(matches!(
+            Vec::<u32>::deserialize(&[0, 0, 0, 0x08], &mut 0, &mut []),
+            Err(DbusError::ValidationError(ValidationError::ArrayTooLong(_)))
+        ));
+    }
+
+    #[test]
+    fn test_builders() {
+        let call = Message::method_call(
+            "org.example.Test",
+            "/org/example/Test",
+            "org.example.Test",
+            "Get",
+        )
+        .unwrap()
+        .with_body(&(String::from("key"), 1_u32))
+        .unwrap();
+        assert_eq!(call.preamble.mtype, MessageType::MethodCall);
+        assert!(call.validate().is_ok());
+        assert!(matches!(
+            call.get_header(&HeaderFieldKind::BodySignature),
+            Some(HeaderFieldValue::String(s)) if s == "su"
+        ));
+        // setting the body again replaces it
+        let call = call.with_body(&()).unwrap();
+        assert!(call.body.is_empty());
+        assert!(call.get_header(&HeaderFieldKind::BodySignature).is_none());
+
+        let (read_end, _write_end) = nix::unistd::pipe().unwrap();
+        let read_end = unsafe { <OwnedFd as std::os::unix::io::FromRawFd>::from_raw_fd(read_end) };
+        let mut bytes = Message::method_call(
+            "org.example.Test",
+            "/org/example/Test",
+            "org.example.Test",
+            "Get",
+        )
+        .unwrap()
+        .with_body(&read_end)
+        .unwrap();
+        // the message has its own duplicate of the fd
+        assert_eq!(bytes.fds.len(), 1);
+        assert_ne!(
+            std::os::unix::io::AsRawFd::as_raw_fd(&bytes.fds[0]),
+            std::os::unix::io::AsRawFd::as_raw_fd(&read_end)
+        );
+        bytes.headers.push(Header {
+            kind: HeaderFieldKind::Sender,
+            value: HeaderFieldValue::String(":1.7".into()),
+        });
+        bytes.serial = 3;
+        let call = bytes;
+
+        let reply = Message::method_return(&call).with_body(&42_u32).unwrap();
+        assert!(reply.validate().is_ok());
+        assert_eq!(reply.reply_serial(), Some(3));
+        assert!(matches!(
+            reply.get_header(&HeaderFieldKind::Destination),
+            Some(HeaderFieldValue::String(s)) if s == ":1.7"
+        ));
+
+        let error = Message::error(&call, "org.example.Error.Failed", "it failed").unwrap();
+        assert!(error.validate().is_ok());
+        assert_eq!(error.preamble.mtype, MessageType::Error);
+        assert_eq!(error.reply_serial(), Some(3));
+        assert_eq!(
+            String::deserialize(&error.body, &mut 0, &mut []).unwrap(),
+            "it failed"
+        );
+
+        // the body is serialized in the endian of the message
+        let signal = Message::signal("/org/example/Test", "org.example.Test", "Changed")
+            .unwrap()
+            .with_endian(Endian::Big)
+            .with_body(&1_u32)
+            .unwrap();
+        assert!(signal.validate().is_ok());
+        assert_eq!(signal.body, vec![0, 0, 0, 1]);
+
+        // names are checked when building, and can also be given already checked
+        let path = ObjectPath::new("/org/example/Test").unwrap();
+        assert!(Message::signal(&path, "org.example.Test", "Changed").is_ok());
+        assert!(matches!(
+            Message::signal(&path, "org.example.Test", "Changed-1"),
+            Err(DbusError::ValidationError(ValidationError::InvalidMember(
+                _
+            )))
+        ));
+        assert!(matches!(
+            Message::method_call("org", &path, "org.example.Test", "Get"),
+            Err(DbusError::ValidationError(ValidationError::InvalidBusName(
+                _
+            )))
+        ));
+        assert!(matches!(
+            Message::error(&call, "Failed", "it failed"),
+            Err(DbusError::ValidationError(
+                ValidationError::InvalidErrorName(_)
+            ))
+        ));
     }
 }
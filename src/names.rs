@@ -0,0 +1,280 @@
+// see https://dbus.freedesktop.org/doc/dbus-specification.html#message-protocol-names
+
+use std::fmt;
+
+use crate::message::{HeaderFieldKind, MessageType};
+use crate::utils::{DbusError, Result};
+
+/// Maximum length of interface, member, error and bus names
+pub const MAX_NAME_LENGTH: usize = 255;
+
+/// Errors found while validating names and messages against the spec.
+/// The bus disconnects us if we send a message which is not valid
+#[derive(Debug, PartialEq, Eq)]
+pub enum ValidationError {
+    /// Object path not starting with `/`, or with empty or invalid elements
+    InvalidObjectPath(String),
+    /// Interface name without at least two valid elements
+    InvalidInterface(String),
+    /// Member name which is empty or has invalid characters
+    InvalidMember(String),
+    /// Error name, which has the same rules as interface names
+    InvalidErrorName(String),
+    /// Unique or well-known bus name which is not valid
+    InvalidBusName(String),
+    /// Header value which is not of the type the header kind requires
+    InvalidHeaderValue(HeaderFieldKind),
+    /// Header required for the message type is missing
+    MissingHeader {
+        mtype: MessageType,
+        kind: HeaderFieldKind,
+    },
+    /// Same header was given more than once
+    DuplicateHeader(HeaderFieldKind),
+    /// Message longer than the 128 MiB maximum, with its length
+    MessageTooLong(usize),
+    /// Array longer than the 64 MiB maximum, with its length in bytes
+    ArrayTooLong(usize),
+    /// Waiting for the reply to a message with `NO_REPLY_EXPECTED`, which the peer
+    /// does not send : such a message must be sent without waiting, using `send_msg`
+    ReplyNotExpected,
+}
+
+impl From<ValidationError> for DbusError {
+    fn from(err: ValidationError) -> DbusError {
+        DbusError::ValidationError(err)
+    }
+}
+
+fn is_element_char(c: u8) -> bool {
+    c.is_ascii_alphanumeric() || c == b'_'
+}
+
+/// Checks that the path is a valid object path
+pub fn validate_object_path(path: &str) -> Result<()> {
+    let valid = match path.strip_prefix('/') {
+        // root is the only path which can end with a `/`
+        Some("") => true,
+        Some(rest) => rest
+            .split('/')
+            .all(|e| !e.is_empty() && e.bytes().all(is_element_char)),
+        None => false,
+    };
+    if !valid {
+        return Err(ValidationError::InvalidObjectPath(path.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Checks the `.` separated name of at least two elements, each of which
+/// consists of given characters, and does not start with a digit unless allowed
+fn valid_dotted_name(name: &str, is_char: fn(u8) -> bool, digit_start: bool) -> bool {
+    if name.is_empty() || name.len() > MAX_NAME_LENGTH {
+        return false;
+    }
+    let mut elements = 0;
+    for element in name.split('.') {
+        let valid = match element.as_bytes().first() {
+            Some(c) => (digit_start || !c.is_ascii_digit()) && element.bytes().all(is_char),
+            None => false,
+        };
+        if !valid {
+            return false;
+        }
+        elements += 1;
+    }
+    elements >= 2
+}
+
+/// Checks that the name is a valid interface name
+pub fn validate_interface(name: &str) -> Result<()> {
+    if !valid_dotted_name(name, is_element_char, false) {
+        return Err(ValidationError::InvalidInterface(name.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Checks that the name is a valid error name
+pub fn validate_error_name(name: &str) -> Result<()> {
+    if !valid_dotted_name(name, is_element_char, false) {
+        return Err(ValidationError::InvalidErrorName(name.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Checks that the name is a valid member name
+pub fn validate_member(name: &str) -> Result<()> {
+    let valid = match name.as_bytes().first() {
+        Some(c) => {
+            !c.is_ascii_digit()
+                && name.len() <= MAX_NAME_LENGTH
+                && name.bytes().all(is_element_char)
+        }
+        None => false,
+    };
+    if !valid {
+        return Err(ValidationError::InvalidMember(name.to_string()).into());
+    }
+    Ok(())
+}
+
+/// Checks that the name is a valid unique name, such as `:1.42`,
+/// or a valid well-known name, such as `org.freedesktop.DBus`
+pub fn validate_bus_name(name: &str) -> Result<()> {
+    let is_char = |c: u8| is_element_char(c) || c == b'-';
+    let valid = match name.strip_prefix(':') {
+        // elements of unique names can start with digits
+        Some(unique) => name.len() <= MAX_NAME_LENGTH && valid_dotted_name(unique, is_char, true),
+        None => valid_dotted_name(name, is_char, false),
+    };
+    if !valid {
+        return Err(ValidationError::InvalidBusName(name.to_string()).into());
+    }
+    Ok(())
+}
+
+macro_rules! validated_name {
+    ($(#[$doc:meta])* $name:ident, $validate:ident) => {
+        $(#[$doc])*
+        #[derive(Debug, Clone, PartialEq, Eq, Hash)]
+        pub struct $name(String);
+
+        impl $name {
+            /// Create it out of given string, checking that it is valid
+            pub fn new(name: impl Into<String>) -> Result<Self> {
+                let name = name.into();
+                $validate(&name)?;
+                Ok(Self(name))
+            }
+
+            pub fn as_str(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl fmt::Display for $name {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(&self.0)
+            }
+        }
+
+        impl AsRef<str> for $name {
+            fn as_ref(&self) -> &str {
+                &self.0
+            }
+        }
+
+        impl From<$name> for String {
+            fn from(name: $name) -> String {
+                name.0
+            }
+        }
+
+        impl TryFrom<&str> for $name {
+            type Error = DbusError;
+            fn try_from(name: &str) -> Result<Self> {
+                Self::new(name)
+            }
+        }
+
+        impl TryFrom<String> for $name {
+            type Error = DbusError;
+            fn try_from(name: String) -> Result<Self> {
+                Self::new(name)
+            }
+        }
+
+        // so the functions taking strings as names can also take names which are already checked
+        impl TryFrom<&$name> for $name {
+            type Error = DbusError;
+            fn try_from(name: &$name) -> Result<Self> {
+                Ok(name.clone())
+            }
+        }
+    };
+}
+
+validated_name!(
+    /// Object path, such as `/org/freedesktop/DBus`
+    ObjectPath,
+    validate_object_path
+);
+validated_name!(
+    /// Interface name, such as `org.freedesktop.DBus.Properties`
+    InterfaceName,
+    validate_interface
+);
+validated_name!(
+    /// Name of a method or signal, such as `GetAll`
+    MemberName,
+    validate_member
+);
+validated_name!(
+    /// Error name, such as `org.freedesktop.DBus.Error.Failed`
+    ErrorName,
+    validate_error_name
+);
+validated_name!(
+    /// Unique or well-known bus name, such as `:1.42` or `org.freedesktop.systemd1`
+    BusName,
+    validate_bus_name
+);
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn is_valid(res: Result<()>) -> bool {
+        match res {
+            Ok(()) => true,
+            Err(DbusError::ValidationError(_)) => false,
+            Err(e) => panic!("unexpected error {:?}", e),
+        }
+    }
+
+    #[test]
+    fn test_names() {
+        for path in ["/", "/org", "/org/freedesktop/DBus", "/_1/a_b"] {
+            assert!(is_valid(validate_object_path(path)), "{}", path);
+        }
+        for path in ["", "org", "//", "/org/", "/org//a", "/org.example", "/a-b"] {
+            assert!(!is_valid(validate_object_path(path)), "{}", path);
+        }
+
+        for name in ["org.freedesktop.DBus", "a.b", "_a.b_1"] {
+            assert!(is_valid(validate_interface(name)), "{}", name);
+            assert!(is_valid(validate_error_name(name)), "{}", name);
+        }
+        let long = format!("a.{}", "b".repeat(MAX_NAME_LENGTH));
+        for name in [
+            "", "org", ".org.a", "org.a.", "org..a", "org.1a", "org.a-b", &long,
+        ] {
+            assert!(!is_valid(validate_interface(name)), "{}", name);
+            assert!(!is_valid(validate_error_name(name)), "{}", name);
+        }
+
+        for name in ["Hello", "_get_1"] {
+            assert!(is_valid(validate_member(name)), "{}", name);
+        }
+        for name in ["", "1a", "a.b", "a-b", "a b"] {
+            assert!(!is_valid(validate_member(name)), "{}", name);
+        }
+
+        for name in [":1.42", ":a.1-2", "org.freedesktop.DBus", "org.a-b.c"] {
+            assert!(is_valid(validate_bus_name(name)), "{}", name);
+        }
+        for name in ["", ":", ":1", "org", "org.1a", ":1..2", "org.a/b", &long] {
+            assert!(!is_valid(validate_bus_name(name)), "{}", name);
+        }
+
+        let path = ObjectPath::new("/org/example").unwrap();
+        assert_eq!(path.as_str(), "/org/example");
+        assert_eq!(String::from(path), "/org/example");
+        assert!(matches!(
+            MemberName::try_from("1a"),
+            Err(DbusError::ValidationError(ValidationError::InvalidMember(
+                _
+            )))
+        ));
+    }
+}
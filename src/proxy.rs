@@ -1,5 +1,9 @@
+use std::os::unix::io::OwnedFd;
+use std::time::Duration;
+
 use crate::dbus::DbusConnection;
 use crate::message::*;
+use crate::names::{BusName, InterfaceName, MemberName, ObjectPath};
 use crate::serialize::DbusSerialize;
 use crate::utils::{DbusError, Result};
 
@@ -7,135 +11,155 @@ use crate::utils::{DbusError, Result};
 /// given destination and path for method calls
 pub struct Proxy<'conn> {
     conn: &'conn mut DbusConnection,
-    dest: String,
-    path: String,
+    dest: BusName,
+    path: ObjectPath,
 }
 
 impl<'conn> Proxy<'conn> {
-    /// create a new proxy for given destination and path over given connection
-    pub fn new(conn: &'conn mut DbusConnection, dest: String, path: String) -> Self {
-        Self { conn, dest, path }
+    /// create a new proxy for given destination and path over given connection,
+    /// which can be given as strings or as names, and are checked to be valid
+    pub fn new(
+        conn: &'conn mut DbusConnection,
+        dest: impl TryInto<BusName, Error = DbusError>,
+        path: impl TryInto<ObjectPath, Error = DbusError>,
+    ) -> Result<Self> {
+        Ok(Self {
+            conn,
+            dest: dest.try_into()?,
+            path: path.try_into()?,
+        })
     }
 
     /// Do a method call for given interface and member by sending given body
-    /// If no body is to be sent, set it as `None`
+    /// If no body is to be sent, set it as `None`.
+    /// This waits for the reply for the default timeout of the connection
     pub fn method_call<Body: DbusSerialize, Output: DbusSerialize>(
         &mut self,
-        interface: &str,
-        member: &str,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+        body: Option<Body>,
+    ) -> Result<Output> {
+        let timeout = self.conn.timeout();
+        self.method_call_with_timeout(interface, member, body, timeout)
+    }
+
+    /// Same as `method_call`, but waits for the reply only for given time,
+    /// returning `DbusError::Timeout` if it does not arrive by then
+    pub fn method_call_with_timeout<Body: DbusSerialize, Output: DbusSerialize>(
+        &mut self,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
         body: Option<Body>,
+        timeout: Duration,
     ) -> Result<Output> {
-        let mut headers = Vec::with_capacity(4);
-
-        // create given headers
-        headers.push(Header {
-            kind: HeaderFieldKind::Path,
-            value: HeaderFieldValue::String(self.path.clone()),
-        });
-        headers.push(Header {
-            kind: HeaderFieldKind::Destination,
-            value: HeaderFieldValue::String(self.dest.clone()),
-        });
-        headers.push(Header {
-            kind: HeaderFieldKind::Interface,
-            value: HeaderFieldValue::String(interface.to_string()),
-        });
-        headers.push(Header {
-            kind: HeaderFieldKind::Member,
-            value: HeaderFieldValue::String(member.to_string()),
-        });
-
-        let mut serialized_body = vec![];
-
-        // if there is some body, serialize it, and set the
-        // body signature header accordingly
-        if let Some(v) = body {
-            headers.push(Header {
-                kind: HeaderFieldKind::BodySignature,
-                value: HeaderFieldValue::String(Body::get_signature()),
-            });
-            v.serialize(&mut serialized_body);
-        }
+        let endian = self.conn.endian();
+        let call = build_method_call(&self.dest, &self.path, interface, member, body, endian)?;
 
         // send the message and get response
-        let reply_messages =
-            self.conn
-                .send_message(MessageType::MethodCall, headers, serialized_body)?;
-
-        // check if there is any error message
-        let error_message: Vec<_> = reply_messages
-            .iter()
-            .filter(|m| m.preamble.mtype == MessageType::Error)
-            .collect();
-
-        // if any error, return error
-        if !error_message.is_empty() {
-            let msg = error_message[0];
-            if msg.body.is_empty() {
-                // this should racrely be the case
-                return Err(DbusError::IncorrectMessage("Unknown Dbus Error".into()));
-            } else {
-                // in error message, first item of the body (if present) is always a string
-                // indicating error
-                let mut ctr = 0;
-                return Err(DbusError::IncorrectMessage(String::deserialize(
-                    &msg.body, &mut ctr,
-                )));
-            }
-        }
-
-        // we basically ignore rest all type of messages
-        let reply: Vec<_> = reply_messages
-            .iter()
-            .filter(|m| m.preamble.mtype == MessageType::MethodReturn)
-            .collect();
-
-        // we are only going to consider first reply, cause... so.
-        let reply = reply[0];
-
-        let headers = &reply.headers;
-        let expected_signature = Output::get_signature();
-
-        // get the signature header
-        let signature_header: Vec<_> = headers
-            .iter()
-            .filter(|h| h.kind == HeaderFieldKind::BodySignature)
-            .collect();
-
-        // This is also something that should never happen
-        // we just check this defensively
-        if signature_header.is_empty() && !reply.body.is_empty() {
-            return Err(DbusError::IncompleteImplementation(
-                "Body non empty, but body signature header missing".to_string(),
-            ));
-        }
-
-        if expected_signature == *"" {
-            // This is for the case when there is no body, i.e. Output = ()
-            // we must do this as the signature header will be
-            // absent in that case, so instead we choose to
-            // parse and return early
-            // This is a bit hacky, but works
-            let mut ctr = 0;
-            return Ok(Output::deserialize(&[], &mut ctr));
-        }
+        let reply = self.conn.call_with_timeout(call, timeout)?;
+
+        parse_method_reply(reply)
+    }
+
+    /// Do a method call without waiting for its reply, setting `NO_REPLY_EXPECTED`
+    /// so the peer does not send one. This returns as soon as the call has been sent
+    pub fn method_call_no_reply<Body: DbusSerialize>(
+        &mut self,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+        body: Option<Body>,
+    ) -> Result<()> {
+        let endian = self.conn.endian();
+        let call = build_method_call(&self.dest, &self.path, interface, member, body, endian)?;
+        self.conn
+            .send_msg(call.with_flags(MessageFlags::NO_REPLY_EXPECTED))?;
+        Ok(())
+    }
+}
+
+/// Creates a method call with given details, with the body serialized in given endian.
+/// The fds in the body are duplicated, so the message can own them
+pub(crate) fn build_method_call<Body: DbusSerialize>(
+    dest: &BusName,
+    path: &ObjectPath,
+    interface: impl TryInto<InterfaceName, Error = DbusError>,
+    member: impl TryInto<MemberName, Error = DbusError>,
+    body: Option<Body>,
+    endian: Endian,
+) -> Result<Message> {
+    let call = Message::method_call(dest, path, interface, member)?.with_endian(endian);
+    match &body {
+        Some(body) => call.with_body(body),
+        None => Ok(call),
+    }
+}
 
-        let actual_signature = match &signature_header[0].value {
-            HeaderFieldValue::String(s) => s,
-            _ => unreachable!("body signature header will always be string type"),
+/// Checks the reply of a method call, returning the error if it is an error reply,
+/// or else the deserialized body if its signature matches the expected output
+pub(crate) fn parse_method_reply<Output: DbusSerialize>(reply: Message) -> Result<Output> {
+    let endian = reply.preamble.endian();
+
+    // if error, return error
+    if reply.preamble.mtype == MessageType::Error {
+        // received messages are validated, so an error always has its name
+        let name = match reply.get_header(&HeaderFieldKind::ErrorName) {
+            Some(HeaderFieldValue::String(name)) => name.clone(),
+            _ => String::new(),
+        };
+        // in error message, first item of the body (if present) is always a string
+        // describing the error
+        let message = if reply.body.is_empty() {
+            String::new()
+        } else {
+            let mut ctr = 0;
+            String::deserialize_endian(&reply.body, &mut ctr, &mut [], endian)?
         };
+        return Err(DbusError::MethodError { name, message });
+    }
 
-        // check that signature returned and type we are trying to deserialize
-        // match as expected
-        if *actual_signature != expected_signature {
-            return Err(DbusError::IncorrectMessage(format!(
-                "reply signature mismatch : expected {}, found {}",
-                expected_signature, actual_signature
-            )));
-        }
+    let headers = &reply.headers;
+    let expected_signature = Output::get_signature();
+
+    // get the signature header
+    let signature_header: Vec<_> = headers
+        .iter()
+        .filter(|h| h.kind == HeaderFieldKind::BodySignature)
+        .collect();
+
+    // This is also something that should never happen
+    // we just check this defensively
+    if signature_header.is_empty() && !reply.body.is_empty() {
+        return Err(DbusError::IncompleteImplementation(
+            "Body non empty, but body signature header missing".to_string(),
+        ));
+    }
 
+    if expected_signature == *"" {
+        // This is for the case when there is no body, i.e. Output = ()
+        // we must do this as the signature header will be
+        // absent in that case, so instead we choose to
+        // parse and return early
+        // This is a bit hacky, but works
         let mut ctr = 0;
-        let ret = Output::deserialize(&reply.body, &mut ctr);
-        Ok(ret)
+        return Output::deserialize(&[], &mut ctr, &mut []);
     }
+
+    // a missing signature header means an empty body, which is not what we expect
+    let actual_signature = match signature_header.first().map(|h| &h.value) {
+        Some(HeaderFieldValue::String(s)) => s.as_str(),
+        _ => "",
+    };
+
+    // check that signature returned and type we are trying to deserialize
+    // match as expected
+    if actual_signature != expected_signature {
+        return Err(DbusError::IncorrectMessage(format!(
+            "reply signature mismatch : expected {}, found {}",
+            expected_signature, actual_signature
+        )));
+    }
+
+    let mut fds: Vec<Option<OwnedFd>> = reply.fds.into_iter().map(Some).collect();
+    let mut ctr = 0;
+    Output::deserialize_endian(&reply.body, &mut ctr, &mut fds, endian)
 }
@@ -1,5 +1,8 @@
-use super::utils::{adjust_padding, align_counter};
+use std::os::unix::io::{AsFd, BorrowedFd, OwnedFd};
 
+use super::message::{Endian, MAX_ARRAY_LENGTH};
+use super::names::ValidationError;
+use super::utils::{adjust_padding, align_counter, take_bytes, take_string, DbusError, Result};
 
 /// This indicates that given type can be serialized as dbus
 /// message body, and has methods needed for that
@@ -8,21 +11,215 @@ pub trait DbusSerialize {
     fn get_signature() -> String
     where
         Self: Sized;
-    /// Serialize the given type into given buffer
+    /// Serialize the given type into given buffer in little endian,
+    /// see `serialize_endian`
+    fn serialize<'a>(&'a self, buf: &mut Vec<u8>, fds: &mut Vec<BorrowedFd<'a>>) {
+        self.serialize_endian(buf, fds, Endian::Little)
+    }
+    /// Serialize the given type into given buffer, with numbers in given endian
     /// This needs to adjust padding before starting serialization, but must not
-    /// pad after last byte of serialized value
-    fn serialize(&self, buf: &mut Vec<u8>);
-    /// Deserialize the given type from given buffer
-    /// The implementation must adjust the counter to required padding boundary 
-    /// before starting deserialization. Also, the caller must have verified that the buffer actually
-    /// contains the given type's value, so this method does not need to do that.
-    /// Finally we should ideally return Result<Self> , but there is only one place where even with
-    /// above constraint, deserialization can fail ; so we choose to panic there instead, and keep the return type Self
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self
+    /// pad after last byte of serialized value. Any file descriptors contained in the value
+    /// must be pushed to fds, and their index in it serialized instead
+    fn serialize_endian<'a>(
+        &'a self,
+        buf: &mut Vec<u8>,
+        fds: &mut Vec<BorrowedFd<'a>>,
+        endian: Endian,
+    );
+    /// Deserialize the given type from given buffer in little endian,
+    /// see `deserialize_endian`
+    fn deserialize(buf: &[u8], counter: &mut usize, fds: &mut [Option<OwnedFd>]) -> Result<Self>
+    where
+        Self: Sized,
+    {
+        Self::deserialize_endian(buf, counter, fds, Endian::Little)
+    }
+    /// Deserialize the given type from given buffer, with numbers in given endian,
+    /// which is the endian of the message the buffer is body of.
+    /// The implementation must adjust the counter to required padding boundary
+    /// before starting deserialization. The caller should have verified that the body signature
+    /// matches the type, but as the body comes from the peer, the implementation must check
+    /// the bounds of everything it reads, and return an error instead of panicking if the buffer
+    /// does not contain a valid value. fds contains the file descriptors received with the
+    /// message, and the implementation should take out the ones it refers to.
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        fds: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self>
     where
         Self: Sized;
 }
 
+/// Maximum depth of nested containers, which the spec limits to 32 arrays and 32 structs
+const MAX_DEPTH: usize = 64;
+
+/// Alignment of the values of given signature, which is decided by their first type
+fn alignment(signature: &[u8]) -> usize {
+    match signature.first() {
+        Some(b'y' | b'g' | b'v') => 1,
+        Some(b'n' | b'q') => 2,
+        Some(b'x' | b't' | b'd' | b'(' | b'{') => 8,
+        // the rest, including strings and arrays, are 4 aligned
+        _ => 4,
+    }
+}
+
+/// Length of the single complete type at the start of given signature
+fn complete_type_len(signature: &[u8], depth: usize) -> Result<usize> {
+    if depth > MAX_DEPTH {
+        return Err(DbusError::IncorrectMessage(
+            "signature is nested too deep".into(),
+        ));
+    }
+    match signature.first() {
+        Some(b'y' | b'b' | b'n' | b'q' | b'i' | b'u' | b'x' | b't' | b'd' | b'h') => Ok(1),
+        Some(b's' | b'o' | b'g' | b'v') => Ok(1),
+        Some(b'a') => Ok(1 + complete_type_len(&signature[1..], depth + 1)?),
+        Some(open @ (b'(' | b'{')) => {
+            let close = if *open == b'(' { b')' } else { b'}' };
+            let mut len = 1;
+            loop {
+                match signature.get(len) {
+                    // empty structs are not allowed
+                    Some(c) if *c == close && len > 1 => return Ok(len + 1),
+                    Some(_) => len += complete_type_len(&signature[len..], depth + 1)?,
+                    None => {
+                        return Err(DbusError::IncorrectMessage(format!(
+                            "unterminated struct in signature {}",
+                            String::from_utf8_lossy(signature)
+                        )))
+                    }
+                }
+            }
+        }
+        Some(c) => Err(DbusError::IncorrectMessage(format!(
+            "invalid type {} in signature",
+            *c as char
+        ))),
+        None => Err(DbusError::IncorrectMessage(
+            "signature ends before a complete type".into(),
+        )),
+    }
+}
+
+/// Skips over a value of given signature, which must be a single complete type,
+/// starting at the counter. This checks the bounds and the lengths of the value, but
+/// not its contents, and is meant for values whose type we do not know in advance,
+/// such as headers of kinds which were added to the spec after this was written
+pub(crate) fn skip_value(
+    buf: &[u8],
+    counter: &mut usize,
+    signature: &str,
+    endian: Endian,
+) -> Result<()> {
+    let signature = signature.as_bytes();
+    if complete_type_len(signature, 0)? != signature.len() {
+        return Err(DbusError::IncorrectMessage(format!(
+            "signature {} is not a single complete type",
+            String::from_utf8_lossy(signature)
+        )));
+    }
+    skip_type(buf, counter, signature, endian, 0)
+}
+
+/// Checks that the body is made of values of given signature, and nothing more.
+/// As the serializers cannot fail, this is how the limits on the lengths of arrays
+/// are enforced for the messages we send
+pub(crate) fn check_body(body: &[u8], signature: &str, endian: Endian) -> Result<()> {
+    let mut signature = signature.as_bytes();
+    let mut counter = 0;
+    while !signature.is_empty() {
+        let len = complete_type_len(signature, 0)?;
+        skip_type(body, &mut counter, &signature[..len], endian, 0)?;
+        signature = &signature[len..];
+    }
+    if counter != body.len() {
+        return Err(DbusError::IncorrectMessage(format!(
+            "body has {} bytes after the values of its signature",
+            body.len() - counter
+        )));
+    }
+    Ok(())
+}
+
+/// Skips over a value of given single complete type
+fn skip_type(
+    buf: &[u8],
+    counter: &mut usize,
+    signature: &[u8],
+    endian: Endian,
+    depth: usize,
+) -> Result<()> {
+    match signature[0] {
+        b'y' => {
+            take_bytes(buf, counter, 1)?;
+        }
+        b'n' | b'q' => {
+            align_counter(counter, 2);
+            take_bytes(buf, counter, 2)?;
+        }
+        b'b' | b'i' | b'u' | b'h' => {
+            align_counter(counter, 4);
+            take_bytes(buf, counter, 4)?;
+        }
+        b'x' | b't' | b'd' => {
+            align_counter(counter, 8);
+            take_bytes(buf, counter, 8)?;
+        }
+        b's' | b'o' => {
+            String::deserialize_endian(buf, counter, &mut [], endian)?;
+        }
+        b'g' => {
+            let length = take_bytes(buf, counter, 1)?[0] as usize;
+            take_string(buf, counter, length)?;
+        }
+        b'v' => {
+            let length = take_bytes(buf, counter, 1)?[0] as usize;
+            let inner = take_string(buf, counter, length)?;
+            let inner = inner.as_bytes();
+            if complete_type_len(inner, depth + 1)? != inner.len() {
+                return Err(DbusError::IncorrectMessage(
+                    "variant signature is not a single complete type".into(),
+                ));
+            }
+            skip_type(buf, counter, inner, endian, depth + 1)?;
+        }
+        b'a' => {
+            align_counter(counter, 4);
+            let length = endian.read_u32(buf, counter)? as usize;
+            if length > MAX_ARRAY_LENGTH {
+                return Err(ValidationError::ArrayTooLong(length).into());
+            }
+            let element = &signature[1..];
+            align_counter(counter, alignment(element));
+            let end = *counter + length;
+            take_bytes(buf, &mut counter.clone(), length)?;
+            while *counter < end {
+                skip_type(buf, counter, element, endian, depth + 1)?;
+            }
+            if *counter != end {
+                return Err(DbusError::IncorrectMessage(format!(
+                    "array elements do not end at its length of {} bytes",
+                    length
+                )));
+            }
+        }
+        // structs and dict entries, whose signature has been checked to be complete
+        _ => {
+            align_counter(counter, 8);
+            let mut pos = 1;
+            while pos < signature.len() - 1 {
+                let len = complete_type_len(&signature[pos..], depth + 1)?;
+                skip_type(buf, counter, &signature[pos..pos + len], endian, depth + 1)?;
+                pos += len;
+            }
+        }
+    }
+    Ok(())
+}
+
 #[derive(Debug)]
 pub struct Variant<T>(pub T);
 
@@ -35,10 +232,16 @@ impl DbusSerialize for () {
     fn get_signature() -> String {
         String::new()
     }
-    fn serialize(&self, _: &mut Vec<u8>) {}
+    fn serialize_endian(&self, _: &mut Vec<u8>, _: &mut Vec<BorrowedFd>, _: Endian) {}
     // for (), we have to ignore body , so we simply clear it out
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        _: &mut [Option<OwnedFd>],
+        _: Endian,
+    ) -> Result<Self> {
         *counter = buf.len();
+        Ok(())
     }
 }
 
@@ -46,14 +249,24 @@ impl<T1: DbusSerialize, T2: DbusSerialize> DbusSerialize for (T1, T2) {
     fn get_signature() -> String {
         format!("{}{}", T1::get_signature(), T2::get_signature())
     }
-    fn serialize(&self, buf: &mut Vec<u8>) {
-        self.0.serialize(buf);
-        self.1.serialize(buf);
+    fn serialize_endian<'a>(
+        &'a self,
+        buf: &mut Vec<u8>,
+        fds: &mut Vec<BorrowedFd<'a>>,
+        endian: Endian,
+    ) {
+        self.0.serialize_endian(buf, fds, endian);
+        self.1.serialize_endian(buf, fds, endian);
     }
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
-        let t1 = T1::deserialize(buf, counter);
-        let t2 = T2::deserialize(buf, counter);
-        (t1, t2)
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        fds: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
+        let t1 = T1::deserialize_endian(buf, counter, fds, endian)?;
+        let t2 = T2::deserialize_endian(buf, counter, fds, endian)?;
+        Ok((t1, t2))
     }
 }
 
@@ -61,21 +274,23 @@ impl DbusSerialize for String {
     fn get_signature() -> String {
         "s".to_string()
     }
-    fn serialize(&self, buf: &mut Vec<u8>) {
+    fn serialize_endian(&self, buf: &mut Vec<u8>, _: &mut Vec<BorrowedFd>, endian: Endian) {
         adjust_padding(buf, 4);
         let length = self.len() as u32;
-        buf.extend_from_slice(&length.to_le_bytes());
+        buf.extend_from_slice(&endian.u32_bytes(length));
 
         buf.extend_from_slice(self.as_bytes());
         buf.push(0); // needs to be null terminated
     }
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        _: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
         align_counter(counter, 4);
-        let length = u32::from_le_bytes(buf[*counter..*counter + 4].try_into().unwrap()) as usize;
-        *counter += 4;
-        let ret = String::from_utf8((&buf[*counter..*counter + length]).into()).unwrap();
-        *counter += length + 1; // +1 accounting for null
-        ret
+        let length = endian.read_u32(buf, counter)? as usize;
+        take_string(buf, counter, length)
     }
 }
 
@@ -83,19 +298,29 @@ impl DbusSerialize for bool {
     fn get_signature() -> String {
         "b".to_string()
     }
-    fn serialize(&self, buf: &mut Vec<u8>) {
+    fn serialize_endian(&self, buf: &mut Vec<u8>, _: &mut Vec<BorrowedFd>, endian: Endian) {
         adjust_padding(buf, 4);
         let val: u32 = match self {
             true => 1,
             false => 0,
         };
-        buf.extend_from_slice(&val.to_le_bytes());
+        buf.extend_from_slice(&endian.u32_bytes(val));
     }
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        _: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
         align_counter(counter, 4);
-        let ret = u32::from_le_bytes(buf[*counter..*counter + 4].try_into().unwrap());
-        *counter += 4;
-        ret != 0
+        match endian.read_u32(buf, counter)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            val => Err(DbusError::IncorrectMessage(format!(
+                "invalid boolean value {}",
+                val
+            ))),
+        }
     }
 }
 
@@ -104,15 +329,18 @@ impl DbusSerialize for u16 {
         "q".to_string()
     }
 
-    fn serialize(&self, buf: &mut Vec<u8>) {
+    fn serialize_endian(&self, buf: &mut Vec<u8>, _: &mut Vec<BorrowedFd>, endian: Endian) {
         adjust_padding(buf, 2);
-        buf.extend_from_slice(&self.to_le_bytes());
+        buf.extend_from_slice(&endian.u16_bytes(*self));
     }
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        _: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
         align_counter(counter, 2);
-        let ret = u16::from_le_bytes(buf[*counter..*counter + 2].try_into().unwrap());
-        *counter += 2;
-        ret
+        endian.read_u16(buf, counter)
     }
 }
 
@@ -121,15 +349,18 @@ impl DbusSerialize for u32 {
         "u".to_string()
     }
 
-    fn serialize(&self, buf: &mut Vec<u8>) {
+    fn serialize_endian(&self, buf: &mut Vec<u8>, _: &mut Vec<BorrowedFd>, endian: Endian) {
         adjust_padding(buf, 4);
-        buf.extend_from_slice(&self.to_le_bytes());
+        buf.extend_from_slice(&endian.u32_bytes(*self));
     }
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        _: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
         align_counter(counter, 4);
-        let ret = u32::from_le_bytes(buf[*counter..*counter + 4].try_into().unwrap());
-        *counter += 4;
-        ret
+        endian.read_u32(buf, counter)
     }
 }
 
@@ -138,15 +369,18 @@ impl DbusSerialize for u64 {
         "t".to_string()
     }
 
-    fn serialize(&self, buf: &mut Vec<u8>) {
+    fn serialize_endian(&self, buf: &mut Vec<u8>, _: &mut Vec<BorrowedFd>, endian: Endian) {
         adjust_padding(buf, 8);
-        buf.extend_from_slice(&self.to_le_bytes());
+        buf.extend_from_slice(&endian.u64_bytes(*self));
     }
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        _: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
         align_counter(counter, 8);
-        let ret = u64::from_le_bytes(buf[*counter..*counter + 8].try_into().unwrap());
-        *counter += 8;
-        ret
+        endian.read_u64(buf, counter)
     }
 }
 
@@ -155,24 +389,55 @@ impl<T: DbusSerialize> DbusSerialize for Vec<T> {
         let sub_type = T::get_signature();
         format!("a{}", sub_type)
     }
-    fn serialize(&self, buf: &mut Vec<u8>) {
+    fn serialize_endian<'a>(
+        &'a self,
+        buf: &mut Vec<u8>,
+        fds: &mut Vec<BorrowedFd<'a>>,
+        endian: Endian,
+    ) {
         adjust_padding(buf, 4);
-        let len = self.len() as u32;
-        buf.extend_from_slice(&len.to_le_bytes());
+        // length is in bytes, so it is filled in once the elements are serialized
+        let length_start = buf.len();
+        buf.extend_from_slice(&[0; 4]);
+        // padding before the first element is there even for empty arrays,
+        // but is not counted in the length
+        adjust_padding(buf, alignment(T::get_signature().as_bytes()));
+        let start = buf.len();
         for elem in self.iter() {
-            elem.serialize(buf);
+            elem.serialize_endian(buf, fds, endian);
         }
+        let length = (buf.len() - start) as u32;
+        buf[length_start..length_start + 4].copy_from_slice(&endian.u32_bytes(length));
     }
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        fds: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
         align_counter(counter, 4);
-        let length = u32::from_le_bytes(buf[*counter..*counter + 4].try_into().unwrap()) as usize;
-        *counter += 4;
-        let mut ret = Vec::with_capacity(length);
-        for _ in 0..length {
-            let elem = T::deserialize(buf, counter);
+        let length = endian.read_u32(buf, counter)? as usize;
+        if length > MAX_ARRAY_LENGTH {
+            return Err(ValidationError::ArrayTooLong(length).into());
+        }
+        align_counter(counter, alignment(T::get_signature().as_bytes()));
+        // check the whole array is there, without taking it, as the elements
+        // must be read from the complete buffer for their alignment
+        let end = *counter + length;
+        take_bytes(buf, &mut counter.clone(), length)?;
+
+        let mut ret = Vec::new();
+        while *counter < end {
+            let elem = T::deserialize_endian(buf, counter, fds, endian)?;
             ret.push(elem);
         }
-        ret
+        if *counter != end {
+            return Err(DbusError::IncorrectMessage(format!(
+                "array elements do not end at its length of {} bytes",
+                length
+            )));
+        }
+        Ok(ret)
     }
 }
 
@@ -180,50 +445,101 @@ impl<T: DbusSerialize> DbusSerialize for Variant<T> {
     fn get_signature() -> String {
         "v".to_string()
     }
-    fn serialize(&self, buf: &mut Vec<u8>) {
+    fn serialize_endian<'a>(
+        &'a self,
+        buf: &mut Vec<u8>,
+        fds: &mut Vec<BorrowedFd<'a>>,
+        endian: Endian,
+    ) {
         // no alignment needed, as variant is 1-align
         let sub_type = T::get_signature();
         let signature_length = sub_type.len() as u8; // signature length must be < 256
         buf.push(signature_length);
         buf.extend_from_slice(sub_type.as_bytes());
         buf.push(0);
-        self.0.serialize(buf);
+        self.0.serialize_endian(buf, fds, endian);
     }
-    fn deserialize(buf: &[u8], counter: &mut usize) -> Self {
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        fds: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
         align_counter(counter, 1);
 
-        let signature_length = buf[*counter] as usize;
-        *counter += 1;
-
-        let actual_signature =
-            String::from_utf8(buf[*counter..*counter + signature_length].into()).unwrap();
-
-        *counter += signature_length + 1; // +1 for null byte
+        let signature_length = take_bytes(buf, counter, 1)?[0] as usize;
+        let actual_signature = take_string(buf, counter, signature_length)?;
 
         // the T itself will take care of padding
         let expected_signature = T::get_signature();
         if expected_signature != actual_signature {
-            panic!(
-                "expected signature {}, found {} instead",
+            return Err(DbusError::IncorrectMessage(format!(
+                "variant signature mismatch : expected {}, found {}",
                 expected_signature, actual_signature
-            );
+            )));
         }
-        let elem: T = T::deserialize(buf, counter);
+        let elem: T = T::deserialize_endian(buf, counter, fds, endian)?;
 
-        Self(elem)
+        Ok(Self(elem))
     }
 }
+impl DbusSerialize for OwnedFd {
+    fn get_signature() -> String {
+        "h".to_string()
+    }
+    // fds are sent out of band, and the body only contains
+    // their index in the array of fds sent along with message
+    fn serialize_endian<'a>(
+        &'a self,
+        buf: &mut Vec<u8>,
+        fds: &mut Vec<BorrowedFd<'a>>,
+        endian: Endian,
+    ) {
+        adjust_padding(buf, 4);
+        let index = fds.len() as u32;
+        buf.extend_from_slice(&endian.u32_bytes(index));
+        fds.push(self.as_fd());
+    }
+    fn deserialize_endian(
+        buf: &[u8],
+        counter: &mut usize,
+        fds: &mut [Option<OwnedFd>],
+        endian: Endian,
+    ) -> Result<Self> {
+        align_counter(counter, 4);
+        let index = endian.read_u32(buf, counter)? as usize;
+        fds.get_mut(index).and_then(Option::take).ok_or_else(|| {
+            DbusError::IncorrectMessage(format!(
+                "message refers to fd {} which was not received",
+                index
+            ))
+        })
+    }
+}
+
 impl DbusSerialize for Structure {
     fn get_signature() -> String {
         "(sv)".to_string()
     }
-    fn serialize(&self, buf: &mut Vec<u8>) {
+    fn serialize_endian<'a>(
+        &'a self,
+        buf: &mut Vec<u8>,
+        fds: &mut Vec<BorrowedFd<'a>>,
+        endian: Endian,
+    ) {
         adjust_padding(buf, 8);
-        self.key.serialize(buf);
-        self.val.serialize(buf);
+        self.key.serialize_endian(buf, fds, endian);
+        self.val.serialize_endian(buf, fds, endian);
     }
-    fn deserialize(_: &[u8], _: &mut usize) -> Self {
-        panic!("we cannot really deref the dyn type to deserialize it");
+    fn deserialize_endian(
+        _: &[u8],
+        _: &mut usize,
+        _: &mut [Option<OwnedFd>],
+        _: Endian,
+    ) -> Result<Self> {
+        Err(DbusError::IncompleteImplementation(
+            "we cannot really deref the dyn type to deserialize it".into(),
+        ))
         // align_counter(counter, 8);
         // let key = String::deserialize(buf, counter);
         // let val = DbusSerialize::deserialize(buf, counter);
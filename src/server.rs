@@ -0,0 +1,300 @@
+use std::os::unix::io::{AsFd, AsRawFd, BorrowedFd, FromRawFd, OwnedFd, RawFd};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use nix::sys::socket;
+
+use crate::address::{DbusAddress, Transport};
+use crate::auth::SaslServer;
+use crate::dbus::DbusConnection;
+use crate::utils::{random_hex, DbusError, Result};
+
+/// How long a client has to authenticate by default
+pub const DEFAULT_AUTH_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Listens on an address for clients connecting directly to us, such as for a control socket.
+/// Clients are authenticated using EXTERNAL, checked against the socket credentials,
+/// and optionally ANONYMOUS. Connections given by this are peer-to-peer ones,
+/// so clients must also connect in peer-to-peer mode, see `DbusConnection::set_peer_to_peer`
+pub struct DbusServer {
+    listener: OwnedFd,
+    /// the address we actually listen on, along with our guid, to give to the clients
+    address: DbusAddress,
+    guid: String,
+    /// uids which can connect using EXTERNAL, only our own by default
+    allowed_uids: Vec<u32>,
+    allow_anonymous: bool,
+    /// how long a client has to authenticate, so a silent one cannot block `accept`
+    auth_timeout: Duration,
+    /// socket file we have created, which is removed when the server is dropped
+    socket_path: Option<PathBuf>,
+}
+
+/// Creates a socket listening on given address
+fn listen_on(address: &DbusAddress) -> Result<OwnedFd> {
+    let addr = match &address.transport {
+        Transport::UnixPath(path) => socket::UnixAddr::new(path)?,
+        Transport::UnixAbstract(name) => socket::UnixAddr::new_abstract(name)?,
+        _ => {
+            return Err(DbusError::ConnectionError(format!(
+                "cannot listen on {} : only unix addresses are supported",
+                address
+            )))
+        }
+    };
+
+    let listener = socket::socket(
+        socket::AddressFamily::Unix,
+        socket::SockType::Stream,
+        socket::SockFlag::SOCK_CLOEXEC,
+        None,
+    )?;
+    // this is safe as we have just created the socket, and this makes sure it
+    // is closed if we cannot listen on it
+    let listener = unsafe { OwnedFd::from_raw_fd(listener) };
+    socket::bind(listener.as_raw_fd(), &addr)?;
+    socket::listen(listener.as_raw_fd(), 128)?;
+    Ok(listener)
+}
+
+impl DbusServer {
+    /// Start listening on given address, such as `unix:path=/run/youki/control` or
+    /// `unix:tmpdir=/tmp`. If multiple `;` separated addresses are given, the first one
+    /// which can be listened on is used
+    pub fn bind(addr: &str) -> Result<Self> {
+        let addresses = DbusAddress::parse_list(addr)?;
+        let guid = random_hex(16)?;
+
+        let mut last_err = None;
+        for address in addresses {
+            let mut address = match address.for_listening() {
+                Ok(a) => a,
+                Err(e) => {
+                    last_err = Some(e);
+                    continue;
+                }
+            };
+            match listen_on(&address) {
+                Ok(listener) => {
+                    let socket_path = match &address.transport {
+                        Transport::UnixPath(path) => Some(path.clone()),
+                        _ => None,
+                    };
+                    address.guid = Some(guid.clone());
+                    return Ok(Self {
+                        listener,
+                        address,
+                        guid,
+                        allowed_uids: vec![nix::unistd::geteuid().as_raw()],
+                        allow_anonymous: false,
+                        auth_timeout: DEFAULT_AUTH_TIMEOUT,
+                        socket_path,
+                    });
+                }
+                Err(e) => last_err = Some(e),
+            }
+        }
+        // parse_list never returns an empty list, so we will always have some error here
+        Err(last_err.unwrap_or_else(|| DbusError::ConnectionError("no address given".into())))
+    }
+
+    /// Get the address clients can connect to, which includes our guid
+    pub fn address(&self) -> &DbusAddress {
+        &self.address
+    }
+
+    /// Get the guid of this server, sent to the clients on authentication
+    pub fn guid(&self) -> &str {
+        &self.guid
+    }
+
+    /// Set the uids which are allowed to connect using EXTERNAL,
+    /// by default only the effective uid of this process is allowed
+    pub fn set_allowed_uids(&mut self, uids: Vec<u32>) {
+        self.allowed_uids = uids;
+    }
+
+    /// Set if clients can connect without any identity using ANONYMOUS, which is not allowed
+    /// by default. Note that this lets anyone who can access the socket connect
+    pub fn set_allow_anonymous(&mut self, allow: bool) {
+        self.allow_anonymous = allow;
+    }
+
+    /// Set how long a client has to authenticate once it has connected,
+    /// which is `DEFAULT_AUTH_TIMEOUT` by default
+    pub fn set_auth_timeout(&mut self, timeout: Duration) {
+        self.auth_timeout = timeout;
+    }
+
+    /// Wait for the next client to connect, and authenticate it. The returned
+    /// connection is ready to use. If the client fails to authenticate, or does not do so
+    /// within the auth timeout, the error is returned and the server can continue
+    /// to accept other clients
+    pub fn accept(&self) -> Result<DbusConnection> {
+        let socket = socket::accept4(self.listener.as_raw_fd(), socket::SockFlag::SOCK_CLOEXEC)?;
+        let deadline = Instant::now() + self.auth_timeout;
+        // this is safe as the kernel has just given it to us
+        let socket = unsafe { OwnedFd::from_raw_fd(socket) };
+        let peer_uid = socket::getsockopt(socket.as_raw_fd(), socket::sockopt::PeerCredentials)
+            .ok()
+            .map(|creds| creds.uid());
+
+        let sasl = SaslServer::new(
+            self.guid.clone(),
+            peer_uid,
+            self.allowed_uids.clone(),
+            self.allow_anonymous,
+            true,
+        );
+        let mut conn = DbusConnection::from_socket(socket, Some(self.address.clone()));
+        conn.accept_auth(self.guid.clone(), sasl, deadline)?;
+        Ok(conn)
+    }
+}
+
+impl Drop for DbusServer {
+    fn drop(&mut self) {
+        if let Some(path) = &self.socket_path {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+impl AsRawFd for DbusServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl AsFd for DbusServer {
+    fn as_fd(&self) -> BorrowedFd<'_> {
+        self.listener.as_fd()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::auth::{AuthError, AuthMechanism, MAX_AUTH_LINE_LENGTH, MAX_REJECTIONS};
+    use crate::message::*;
+    use crate::serialize::DbusSerialize;
+
+    #[test]
+    fn test_server() {
+        let server = DbusServer::bind("unix:tmpdir=/tmp").unwrap();
+        assert!(matches!(
+            server.address().transport,
+            Transport::UnixAbstract(_)
+        ));
+        let address = server.address().to_string();
+
+        let client = std::thread::spawn(move || {
+            let mut conn = DbusConnection::new(&address).unwrap();
+            conn.set_peer_to_peer(true);
+            // the guid in address is checked against the one server sends
+            conn.authenticate(None).unwrap();
+            assert!(conn.unix_fd_enabled());
+
+            let mut proxy = conn.proxy("org.example.Test", "/org/example/Test").unwrap();
+            let reply: u32 = proxy
+                .method_call::<(), _>("org.example.Test", "Get", None)
+                .unwrap();
+            assert_eq!(reply, 42);
+        });
+
+        let mut conn = server.accept().unwrap();
+        assert!(conn.unix_fd_enabled());
+        assert_eq!(conn.server_guid(), Some(server.guid()));
+
+        let call = conn.read_message(None).unwrap().unwrap();
+        let mut body = vec![];
+        42_u32.serialize(&mut body, &mut vec![]);
+        conn.send(
+            MessageType::MethodReturn,
+            vec![
+                Header {
+                    kind: HeaderFieldKind::ReplySerial,
+                    value: HeaderFieldValue::U32(call.serial),
+                },
+                Header {
+                    kind: HeaderFieldKind::BodySignature,
+                    value: HeaderFieldValue::String("u".into()),
+                },
+            ],
+            body,
+            vec![],
+        )
+        .unwrap();
+        client.join().unwrap();
+    }
+
+    #[test]
+    fn test_server_anonymous() {
+        let path = std::env::temp_dir().join(format!("dbus-native-server-{}", std::process::id()));
+        let mut server = DbusServer::bind(&format!("unix:path={}", path.display())).unwrap();
+        let address = server.address().to_string();
+
+        // anonymous clients are rejected unless allowed
+        let addr = address.clone();
+        let client = std::thread::spawn(move || {
+            let mut conn = DbusConnection::new(&addr).unwrap();
+            conn.set_peer_to_peer(true);
+            conn.authenticate_with(vec![AuthMechanism::Anonymous(None)])
+        });
+        assert!(server.accept().is_err());
+        assert!(matches!(
+            client.join().unwrap(),
+            Err(DbusError::AuthError(AuthError::Rejected(_)))
+        ));
+
+        server.set_allow_anonymous(true);
+        let client = std::thread::spawn(move || {
+            let mut conn = DbusConnection::new(&address).unwrap();
+            conn.set_peer_to_peer(true);
+            conn.authenticate_with(vec![AuthMechanism::Anonymous(None)])
+        });
+        server.accept().unwrap();
+        client.join().unwrap().unwrap();
+
+        // socket file is removed along with the server
+        assert!(path.exists());
+        drop(server);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_server_limits() {
+        let path = std::env::temp_dir().join(format!("dbus-native-limits-{}", std::process::id()));
+        let mut server = DbusServer::bind(&format!("unix:path={}", path.display())).unwrap();
+        server.set_auth_timeout(Duration::from_millis(100));
+        let connect = |data: &[u8]| {
+            let mut client = std::os::unix::net::UnixStream::connect(&path).unwrap();
+            std::io::Write::write_all(&mut client, data).unwrap();
+            client
+        };
+
+        // a client which says nothing does not block the server
+        let _client = connect(b"");
+        assert!(matches!(server.accept(), Err(DbusError::Timeout(_))));
+
+        // nor does one which never ends its line
+        let mut line = b"\0AUTH EXTERNAL ".to_vec();
+        line.resize(MAX_AUTH_LINE_LENGTH + 2, b'3');
+        let _client = connect(&line);
+        assert!(matches!(
+            server.accept(),
+            Err(DbusError::AuthError(AuthError::LineTooLong))
+        ));
+
+        // or one which keeps on trying
+        let mut lines = b"\0".to_vec();
+        for _ in 0..=MAX_REJECTIONS {
+            lines.extend_from_slice(b"AUTH ANONYMOUS\r\n");
+        }
+        let _client = connect(&lines);
+        assert!(matches!(
+            server.accept(),
+            Err(DbusError::AuthError(AuthError::TooManyRejections))
+        ));
+    }
+}
@@ -0,0 +1,449 @@
+use std::collections::HashMap;
+use std::os::unix::io::{AsRawFd, OwnedFd};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use nix::sys::socket;
+
+use crate::dbus::DbusConnection;
+use crate::message::*;
+use crate::names::{BusName, InterfaceName, MemberName, ObjectPath};
+use crate::proxy::{build_method_call, parse_method_reply};
+use crate::serialize::DbusSerialize;
+use crate::utils::{lock, DbusError, Result};
+
+/// Where the dispatcher thread should send the messages it receives
+struct Routes {
+    /// callers waiting for the reply to message with given serial
+    pending: HashMap<u32, Sender<Message>>,
+    /// receivers of everything which is not a reply, such as signals
+    subscribers: Vec<Sender<Message>>,
+    /// false once the dispatcher has stopped, after which nothing will be received
+    connected: bool,
+}
+
+/// The part of connection used for sending, shared by all the handles
+struct Writer {
+    conn: Mutex<DbusConnection>,
+    routes: Arc<Mutex<Routes>>,
+}
+
+impl Drop for Writer {
+    fn drop(&mut self) {
+        // the dispatcher thread reads from a duplicate of our socket, so shutting the socket
+        // down makes its read return, after which it will exit
+        let fd = lock(&self.conn).as_raw_fd();
+        let _ = socket::shutdown(fd, socket::Shutdown::Both);
+    }
+}
+
+/// A dbus connection which can be cloned and shared between threads. A background
+/// thread reads all incoming messages, giving the replies to the callers waiting for them,
+/// and all other messages, such as signals, to the subscribers. Messages received
+/// while there are no subscribers are dropped. The connection is closed once all
+/// of the clones have been dropped.
+/// Reconnecting is not supported, as the dispatcher thread reads from its own duplicate
+/// of the socket : any reconnect policy set on the given connection is cleared,
+/// and once disconnected, a new shared connection must be made
+#[derive(Clone)]
+pub struct SharedConnection {
+    writer: Arc<Writer>,
+    unique_name: Option<String>,
+    unix_fd: bool,
+    endian: Endian,
+    timeout: Duration,
+}
+
+/// Reads messages until the connection closes, sending them where they are needed
+fn dispatch(mut reader: DbusConnection, routes: Arc<Mutex<Routes>>) {
+    // messages the connection had already received go first, so the order is kept
+    for msg in reader.drain_incoming() {
+        route(&mut lock(&routes), msg);
+    }
+    loop {
+        match reader.read_message(None) {
+            Ok(Some(msg)) => route(&mut lock(&routes), msg),
+            // no deadline was given, so this does not happen
+            Ok(None) => {}
+            // a single malformed message can be skipped, as it has already been taken
+            // out of the buffer
+            Err(DbusError::IncorrectMessage(_) | DbusError::ValidationError(_)) => {}
+            Err(_) => break,
+        }
+    }
+
+    let mut routes = lock(&routes);
+    routes.connected = false;
+    // dropping the senders wakes up all waiting callers
+    routes.pending.clear();
+    // this gives the subscribers the Disconnected signal
+    for msg in reader.drain_incoming() {
+        deliver(&mut routes, msg);
+    }
+    routes.subscribers.clear();
+}
+
+/// Gives a reply to the caller waiting for it, and any other message to the subscribers
+fn route(routes: &mut Routes, msg: Message) {
+    match msg.reply_serial() {
+        Some(serial) => {
+            // if no one is waiting, the call has timed out and the reply is discarded
+            if let Some(tx) = routes.pending.remove(&serial) {
+                let _ = tx.send(msg);
+            }
+        }
+        None => deliver(routes, msg),
+    }
+}
+
+/// Gives the message to all the subscribers, forgetting the ones which have gone away
+fn deliver(routes: &mut Routes, msg: Message) {
+    let mut msg = Some(msg);
+    let count = routes.subscribers.len();
+    let mut i = 0;
+    routes.subscribers.retain(|tx| {
+        i += 1;
+        // last one gets the original, so we do not need to copy it needlessly
+        let m = if i == count {
+            msg.take().unwrap()
+        } else {
+            match msg.as_ref().unwrap().try_clone() {
+                Ok(m) => m,
+                // like a malformed message, this one is skipped for the subscriber,
+                // as giving it without the fds its header counts would be worse
+                Err(_) => return true,
+            }
+        };
+        tx.send(m).is_ok()
+    });
+}
+
+impl SharedConnection {
+    /// Create a shared connection out of given authenticated connection,
+    /// starting the dispatcher thread for it. The connection is set back to blocking mode,
+    /// as nothing would flush the writes left buffered otherwise
+    pub fn new(mut conn: DbusConnection) -> Result<Self> {
+        // reconnecting would replace the socket of the writer only
+        conn.set_reconnect_policy(None);
+        conn.set_nonblocking(false)?;
+        conn.flush()?;
+        let reader = conn.split_reader()?;
+        let routes = Arc::new(Mutex::new(Routes {
+            pending: HashMap::new(),
+            subscribers: Vec::new(),
+            connected: conn.is_connected(),
+        }));
+
+        let unique_name = conn.unique_name().map(|n| n.to_string());
+        let unix_fd = conn.unix_fd_enabled();
+        let endian = conn.endian();
+        let timeout = conn.timeout();
+
+        let dispatcher_routes = routes.clone();
+        std::thread::Builder::new()
+            .name("dbus-dispatcher".into())
+            .spawn(move || dispatch(reader, dispatcher_routes))?;
+
+        Ok(Self {
+            writer: Arc::new(Writer {
+                conn: Mutex::new(conn),
+                routes,
+            }),
+            unique_name,
+            unix_fd,
+            endian,
+            timeout,
+        })
+    }
+
+    /// Get the unique name assigned to the connection by the bus
+    pub fn unique_name(&self) -> Option<&str> {
+        self.unique_name.as_deref()
+    }
+
+    /// Check if fds can be passed over this connection
+    pub fn unix_fd_enabled(&self) -> bool {
+        self.unix_fd
+    }
+
+    /// Check if the connection is still open
+    pub fn is_connected(&self) -> bool {
+        lock(&self.writer.routes).connected
+    }
+
+    /// Get a receiver for all messages which are not replies to our calls,
+    /// such as signals. Each subscriber gets a copy of every message received after
+    /// subscribing, with its own duplicates of the fds sent along with it.
+    /// The receiver is closed after the Disconnected signal once the connection closes
+    pub fn subscribe(&self) -> Receiver<Message> {
+        let (tx, rx) = mpsc::channel();
+        let mut routes = lock(&self.writer.routes);
+        if routes.connected {
+            routes.subscribers.push(tx);
+        }
+        rx
+    }
+
+    /// Send the message without waiting for any reply, see `DbusConnection::send`.
+    /// Returns the serial of the sent message
+    pub fn send(
+        &self,
+        mtype: MessageType,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+    ) -> Result<u32> {
+        lock(&self.writer.conn).send(mtype, headers, body, fds)
+    }
+
+    /// Send the message with given flags, see `DbusConnection::send_with_flags`
+    pub fn send_with_flags(
+        &self,
+        mtype: MessageType,
+        flags: MessageFlags,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+    ) -> Result<u32> {
+        lock(&self.writer.conn).send_with_flags(mtype, flags, headers, body, fds)
+    }
+
+    /// Send given message without waiting for any reply, see `DbusConnection::send_msg`
+    pub fn send_msg(&self, message: Message) -> Result<u32> {
+        lock(&self.writer.conn).send_msg(message)
+    }
+
+    /// Send the message and wait for its reply for the default timeout of the connection,
+    /// see `DbusConnection::send_message`
+    pub fn send_message(
+        &self,
+        mtype: MessageType,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+    ) -> Result<Message> {
+        self.send_message_with_timeout(mtype, headers, body, fds, self.timeout)
+    }
+
+    /// Send the message and wait for its reply for the given time
+    pub fn send_message_with_timeout(
+        &self,
+        mtype: MessageType,
+        headers: Vec<Header>,
+        body: Vec<u8>,
+        fds: Vec<OwnedFd>,
+        timeout: Duration,
+    ) -> Result<Message> {
+        let mut message = Message::new(mtype, 0, headers, body).with_endian(self.endian);
+        message.fds = fds;
+        self.call_with_timeout(message, timeout)
+    }
+
+    /// Send given method call and wait for its reply for the default timeout
+    /// of the connection, see `DbusConnection::call`
+    pub fn call(&self, message: Message) -> Result<Message> {
+        self.call_with_timeout(message, self.timeout)
+    }
+
+    /// Same as `call`, but waits for the reply only for given time
+    pub fn call_with_timeout(&self, message: Message, timeout: Duration) -> Result<Message> {
+        message.check_reply_expected()?;
+        let (tx, rx) = mpsc::channel();
+        let serial = {
+            let mut conn = lock(&self.writer.conn);
+            // we register for the reply before sending, so the dispatcher cannot get it
+            // before we are ready. The routes must not be held while sending, as that can
+            // block, and the dispatcher needs them to keep reading
+            let serial = conn.get_msg_id();
+            {
+                let mut routes = lock(&self.writer.routes);
+                if !routes.connected {
+                    return Err(DbusError::Disconnected);
+                }
+                routes.pending.insert(serial, tx);
+            }
+            if let Err(e) = conn.send_msg_with_serial(message, serial) {
+                lock(&self.writer.routes).pending.remove(&serial);
+                return Err(e);
+            }
+            serial
+        };
+
+        match rx.recv_timeout(timeout) {
+            Ok(msg) => Ok(msg),
+            Err(RecvTimeoutError::Timeout) => {
+                lock(&self.writer.routes).pending.remove(&serial);
+                Err(DbusError::Timeout(format!(
+                    "no reply received for message {}",
+                    serial
+                )))
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(DbusError::Disconnected),
+        }
+    }
+
+    /// Get a proxy for given destination and path, which can be sent to other threads,
+    /// see `Proxy::new`
+    pub fn proxy(
+        &self,
+        destination: impl TryInto<BusName, Error = DbusError>,
+        path: impl TryInto<ObjectPath, Error = DbusError>,
+    ) -> Result<SharedProxy> {
+        Ok(SharedProxy {
+            conn: self.clone(),
+            dest: destination.try_into()?,
+            path: path.try_into()?,
+        })
+    }
+}
+
+/// Same as `Proxy`, but over a shared connection
+#[derive(Clone)]
+pub struct SharedProxy {
+    conn: SharedConnection,
+    dest: BusName,
+    path: ObjectPath,
+}
+
+impl SharedProxy {
+    /// Do a method call for given interface and member by sending given body,
+    /// see `Proxy::method_call`
+    pub fn method_call<Body: DbusSerialize, Output: DbusSerialize>(
+        &self,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+        body: Option<Body>,
+    ) -> Result<Output> {
+        self.method_call_with_timeout(interface, member, body, self.conn.timeout)
+    }
+
+    /// Same as `method_call`, but waits for the reply only for given time
+    pub fn method_call_with_timeout<Body: DbusSerialize, Output: DbusSerialize>(
+        &self,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+        body: Option<Body>,
+        timeout: Duration,
+    ) -> Result<Output> {
+        let call = build_method_call(
+            &self.dest,
+            &self.path,
+            interface,
+            member,
+            body,
+            self.conn.endian,
+        )?;
+        let reply = self.conn.call_with_timeout(call, timeout)?;
+        parse_method_reply(reply)
+    }
+
+    /// Do a method call without waiting for its reply, see `Proxy::method_call_no_reply`
+    pub fn method_call_no_reply<Body: DbusSerialize>(
+        &self,
+        interface: impl TryInto<InterfaceName, Error = DbusError>,
+        member: impl TryInto<MemberName, Error = DbusError>,
+        body: Option<Body>,
+    ) -> Result<()> {
+        let call = build_method_call(
+            &self.dest,
+            &self.path,
+            interface,
+            member,
+            body,
+            self.conn.endian,
+        )?;
+        self.conn
+            .send_msg(call.with_flags(MessageFlags::NO_REPLY_EXPECTED))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::dbus::test::{listen_abstract, serve_reversed_replies};
+
+    #[test]
+    fn test_shared_connection() {
+        let name = format!("dbus-native-test-shared-{}", std::process::id());
+        let listener = listen_abstract(&name);
+
+        let server = std::thread::spawn(move || {
+            let fd = serve_reversed_replies(listener);
+
+            // wait for the client to hang up
+            let mut buf = [0];
+            while socket::recv(fd, &mut buf, socket::MsgFlags::empty()).unwrap() > 0 {}
+            let _ = nix::unistd::close(fd);
+        });
+
+        let mut conn = DbusConnection::new(&format!("unix:abstract={}", name)).unwrap();
+        conn.authenticate(None).unwrap();
+        let conn = SharedConnection::new(conn).unwrap();
+        assert_eq!(conn.unique_name(), Some(":1.1"));
+        let signals = conn.subscribe();
+
+        let proxy = conn.proxy("org.example.Test", "/org/example/Test").unwrap();
+        let first = {
+            let proxy = proxy.clone();
+            std::thread::spawn(move || {
+                proxy
+                    .method_call::<(), u32>("org.example.Test", "First", None)
+                    .unwrap()
+            })
+        };
+        // make sure the first call is sent before the second one
+        while lock(&conn.writer.routes).pending.is_empty() {
+            std::thread::yield_now();
+        }
+        let second: u32 = proxy
+            .method_call::<(), _>("org.example.Test", "Second", None)
+            .unwrap();
+        assert_eq!(second, 2);
+        assert_eq!(first.join().unwrap(), 1);
+
+        let signal = signals.recv().unwrap();
+        assert_eq!(signal.preamble.mtype, MessageType::Signal);
+
+        // a call which cannot be sent does not stay registered for a reply
+        let res = conn.call(Message::new(MessageType::MethodCall, 0, vec![], vec![]));
+        assert!(matches!(res, Err(DbusError::ValidationError(_))));
+        assert!(lock(&conn.writer.routes).pending.is_empty());
+
+        // dropping all handles closes the connection
+        drop(proxy);
+        drop(conn);
+        server.join().unwrap();
+    }
+
+    #[test]
+    fn test_shared_queued() {
+        let (mut conn, mut server) = DbusConnection::pair().unwrap();
+        let signal = |member| Message::signal("/org/example/Test", "org.example.Test", member);
+
+        // a signal which was queued before sharing the connection
+        conn.set_nonblocking(true).unwrap();
+        server.send_msg(signal("First").unwrap()).unwrap();
+        while conn.dispatch_pending().unwrap() == 0 {
+            let _ = conn.read_available().unwrap();
+        }
+        let conn = SharedConnection::new(conn).unwrap();
+        assert!(!lock(&conn.writer.conn).is_nonblocking().unwrap());
+
+        let signals = conn.subscribe();
+        server.send_msg(signal("Second").unwrap()).unwrap();
+        drop(server);
+        let members: Vec<_> = signals
+            .iter()
+            .map(|m| match m.get_header(&HeaderFieldKind::Member) {
+                Some(HeaderFieldValue::String(m)) => m.clone(),
+                _ => panic!("message without member"),
+            })
+            .collect();
+        // it is dispatched right away, so it is seen only if we subscribed early enough,
+        // but never after the messages received later
+        let later = members.iter().skip_while(|m| *m == "First");
+        assert_eq!(later.collect::<Vec<_>>(), vec!["Second", "Disconnected"]);
+    }
+}
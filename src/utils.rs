@@ -1,18 +1,95 @@
+use crate::address::AddressError;
+use crate::auth::AuthError;
+use crate::names::ValidationError;
+use std::sync::{Mutex, MutexGuard};
+
 #[derive(Debug)]
 pub enum DbusError {
     IncompleteImplementation(String),
     IncorrectMessage(String),
     ConnectionError(String),
+    AddressError(AddressError),
+    AuthError(AuthError),
+    /// a name or message is not valid according to the spec
+    ValidationError(ValidationError),
+    /// no reply to a method call was received within the timeout
+    Timeout(String),
+    /// the peer replied to a method call with an error of given name, such as
+    /// `org.freedesktop.DBus.Error.AccessDenied`, and the message sent with it, if any
+    MethodError {
+        name: String,
+        message: String,
+    },
+    /// the connection was closed by the peer, or was reset
+    Disconnected,
 }
 
 pub type Result<T> = std::result::Result<T, DbusError>;
 
+/// Locks the mutex, ignoring if some thread panicked while holding it, as the state
+/// we guard with mutexes is never left half updated
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(|e| e.into_inner())
+}
+
 impl From<nix::Error> for DbusError {
     fn from(err: nix::Error) -> DbusError {
         DbusError::ConnectionError(err.to_string())
     }
 }
 
+impl From<std::io::Error> for DbusError {
+    fn from(err: std::io::Error) -> DbusError {
+        DbusError::ConnectionError(err.to_string())
+    }
+}
+
+/// Get given number of random bytes from the kernel, hex encoded
+pub fn random_hex(len: usize) -> Result<String> {
+    use std::io::Read;
+    let mut bytes = vec![0; len];
+    std::fs::File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+/// Take given number of bytes from the buffer starting at the counter, advancing
+/// the counter past them. This fails if the buffer is too short, which can only
+/// happen for a truncated or otherwise malformed message
+pub fn take_bytes<'a>(buf: &'a [u8], ctr: &mut usize, len: usize) -> Result<&'a [u8]> {
+    let end = match ctr.checked_add(len) {
+        Some(end) if end <= buf.len() => end,
+        _ => {
+            return Err(DbusError::IncorrectMessage(format!(
+                "message truncated : needed {} bytes at offset {}, but it has only {}",
+                len,
+                ctr,
+                buf.len()
+            )))
+        }
+    };
+    let ret = &buf[*ctr..end];
+    *ctr = end;
+    Ok(ret)
+}
+
+/// Take a string of given length from the buffer starting at the counter, along with
+/// the null byte after it, checking that it is valid utf-8 without any other null bytes
+pub fn take_string(buf: &[u8], ctr: &mut usize, len: usize) -> Result<String> {
+    let bytes = take_bytes(buf, ctr, len)?;
+    if take_bytes(buf, ctr, 1)? != [0] {
+        return Err(DbusError::IncorrectMessage(
+            "string is not null terminated".into(),
+        ));
+    }
+    if bytes.contains(&0) {
+        return Err(DbusError::IncorrectMessage(
+            "string contains a null byte".into(),
+        ));
+    }
+    String::from_utf8(bytes.to_vec())
+        .map_err(|e| DbusError::IncorrectMessage(format!("string is not valid utf-8 : {}", e)))
+}
+
 pub fn adjust_padding(buf: &mut Vec<u8>, align: usize) {
     if align == 1 {
         return; // no padding is required for 1-alignment
@@ -76,4 +153,26 @@ mod test {
         assert_eq!(buf.len(), 4);
         assert_eq!(buf, vec![1, 2, 3, 0]);
     }
+
+    #[test]
+    fn test_take() {
+        let buf = b"abc\0de\xff\0";
+        let mut ctr = 0;
+        assert_eq!(take_bytes(buf, &mut ctr, 2).unwrap(), b"ab");
+        assert_eq!(ctr, 2);
+        assert!(take_bytes(buf, &mut ctr, 7).is_err());
+        assert!(take_bytes(buf, &mut ctr, usize::MAX).is_err());
+
+        let mut ctr = 0;
+        assert_eq!(take_string(buf, &mut ctr, 3).unwrap(), "abc");
+        assert_eq!(ctr, 4);
+        // not valid utf-8
+        assert!(take_string(buf, &mut ctr, 3).is_err());
+        // not null terminated
+        assert!(take_string(buf, &mut 0, 2).is_err());
+        // null byte inside the string
+        assert!(take_string(buf, &mut 0, 7).is_err());
+        // missing null byte at the end
+        assert!(take_string(buf, &mut 4, 4).is_err());
+    }
 }